@@ -0,0 +1,527 @@
+use super::{
+    histogram_data_point_to_time_series::bucket_bounds,
+    utils::{normalize_label_key, time_series_key},
+    UNIQUE_IDENTIFIER_KEY,
+};
+use crate::gcloud_sdk::google::{
+    api::{
+        distribution::bucket_options::{Explicit, Exponential, Options},
+        Distribution,
+    },
+    monitoring::v3::{typed_value::Value, Point, TimeSeries},
+};
+use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+use std::collections::{BTreeMap, HashMap};
+
+/// Which data point attributes become labels and under which key.
+/// Keys are compared after label normalization, so `http.route` and
+/// `http_route` name the same attribute.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttributeRules {
+    /// allow: when set, only these attributes are kept.
+    pub allow: Option<Vec<String>>,
+    /// deny: attributes that are dropped, e.g. high-cardinality user IDs.
+    pub deny: Vec<String>,
+    /// rename: attribute key -> label key.
+    pub rename: HashMap<String, String>,
+}
+
+impl AttributeRules {
+    fn normalized(self) -> Self {
+        Self {
+            allow: self
+                .allow
+                .map(|allow| allow.iter().map(|key| normalize_label_key(key)).collect()),
+            deny: self.deny.iter().map(|key| normalize_label_key(key)).collect(),
+            rename: self
+                .rename
+                .iter()
+                .map(|(from, to)| (normalize_label_key(from), normalize_label_key(to)))
+                .collect(),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allow.is_none() && self.deny.is_empty() && self.rename.is_empty()
+    }
+
+    fn keeps(&self, key: &str) -> bool {
+        self.allow.as_ref().map_or(true, |allow| allow.iter().any(|k| k == key)) && !self.deny.iter().any(|k| k == key)
+    }
+}
+
+/// Applies the global and the per-metric `AttributeRules`. An attribute is kept
+/// if both rule sets keep it, the per-metric renaming wins over the global one.
+#[derive(Debug, Default)]
+pub(crate) struct AttributeFilter {
+    global: AttributeRules,
+    per_metric: HashMap<String, AttributeRules>,
+}
+
+impl AttributeFilter {
+    pub(crate) fn new(global: AttributeRules, per_metric: HashMap<String, AttributeRules>) -> Self {
+        Self {
+            global: global.normalized(),
+            per_metric: per_metric
+                .into_iter()
+                .map(|(metric, rules)| (metric, rules.normalized()))
+                .collect(),
+        }
+    }
+
+    fn is_noop(&self, metric: &str) -> bool {
+        self.global.is_empty() && self.per_metric.get(metric).map_or(true, AttributeRules::is_empty)
+    }
+
+    /// Label key of the normalized attribute `key` of `metric`, None if the attribute is dropped
+    pub(crate) fn label_key(&self, metric: &str, key: &str) -> Option<String> {
+        let metric_rules = self.per_metric.get(metric);
+        if !self.global.keeps(key) || !metric_rules.map_or(true, |rules| rules.keeps(key)) {
+            return None;
+        }
+        metric_rules
+            .and_then(|rules| rules.rename.get(key))
+            .or_else(|| self.global.rename.get(key))
+            .cloned()
+            .or_else(|| Some(key.to_string()))
+    }
+
+    /// Drops and renames the labels of the series of `metric`. Returns
+    /// whether a label was dropped or renamed.
+    pub(crate) fn apply(&self, metric: &str, series: &mut [TimeSeries]) -> bool {
+        if self.is_noop(metric) {
+            return false;
+        }
+        let mut rewritten = false;
+        for ts in series {
+            let Some(m) = ts.metric.as_mut() else {
+                continue;
+            };
+            // sorted, so that the first of two attributes renamed to the same key wins
+            let labels = m.labels.drain().collect::<BTreeMap<String, String>>();
+            for (key, value) in labels {
+                let label_key = if key == UNIQUE_IDENTIFIER_KEY {
+                    Some(key)
+                } else {
+                    let label_key = self.label_key(metric, &key);
+                    rewritten |= label_key.as_ref() != Some(&key);
+                    label_key
+                };
+                if let Some(label_key) = label_key {
+                    m.labels.entry(label_key).or_insert(value);
+                }
+            }
+        }
+        rewritten
+    }
+}
+
+/// How to combine the points of series that collapsed onto the same labels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Reaggregation {
+    /// Add the values, for sums and histograms.
+    Sum,
+    /// Keep the latest value, for gauges.
+    LastValue,
+}
+
+impl Reaggregation {
+    pub(crate) fn of(data: &AggregatedMetrics) -> Self {
+        let is_gauge = matches!(
+            data,
+            AggregatedMetrics::F64(MetricData::Gauge(_))
+                | AggregatedMetrics::I64(MetricData::Gauge(_))
+                | AggregatedMetrics::U64(MetricData::Gauge(_))
+        );
+        if is_gauge {
+            Self::LastValue
+        } else {
+            Self::Sum
+        }
+    }
+}
+
+/// Cloud Monitoring accepts at most 200 buckets per distribution
+const MAX_BUCKETS: i64 = 200;
+/// Relative tolerance of the comparison of bucket bounds computed from different layouts
+const BOUND_TOLERANCE: f64 = 1e-9;
+
+/// Merges the series of one metric sharing the same labels and resource, e.g.
+/// after attributes were dropped. Each series holds a single point. Distributions
+/// whose buckets can't be folded into common ones stay separate series.
+pub(crate) fn reaggregate(series: Vec<TimeSeries>, reaggregation: Reaggregation) -> Vec<TimeSeries> {
+    let mut merged: Vec<TimeSeries> = Vec::with_capacity(series.len());
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for ts in series {
+        let key = time_series_key(&ts);
+        match index.get(&key) {
+            Some(&i) if reaggregation == Reaggregation::LastValue || mergeable(&merged[i], &ts) => {
+                merge_series(&mut merged[i], ts, reaggregation)
+            }
+            Some(_) => {
+                tracing::warn!(
+                    metric_type = ts.metric.as_ref().map(|m| m.r#type.as_str()).unwrap_or_default(),
+                    "GCPMetricsExporter: cant merge distributions without common buckets, series kept separate"
+                );
+                merged.push(ts);
+            }
+            None => {
+                index.insert(key, merged.len());
+                merged.push(ts);
+            }
+        }
+    }
+    merged
+}
+
+/// Whether the points of two series can be summed: distributions need common buckets
+fn mergeable(a: &TimeSeries, b: &TimeSeries) -> bool {
+    fn buckets(ts: &TimeSeries) -> Option<Option<&Options>> {
+        match ts.points.first()?.value.as_ref()?.value.as_ref()? {
+            Value::DistributionValue(distribution) => {
+                Some(distribution.bucket_options.as_ref().and_then(|o| o.options.as_ref()))
+            }
+            _ => None,
+        }
+    }
+    match (buckets(a), buckets(b)) {
+        (Some(a), Some(b)) if a != b => a.zip(b).is_some_and(|(a, b)| common_bucket_options(a, b).is_some()),
+        _ => true,
+    }
+}
+
+fn merge_series(into: &mut TimeSeries, other: TimeSeries, reaggregation: Reaggregation) {
+    let (Some(point), Some(other_point)) = (into.points.first_mut(), other.points.into_iter().next()) else {
+        return;
+    };
+    match reaggregation {
+        Reaggregation::LastValue => {
+            if end_time(&other_point) >= end_time(point) {
+                *point = other_point;
+            }
+        }
+        Reaggregation::Sum => merge_points(point, other_point),
+    }
+}
+
+fn end_time(point: &Point) -> Option<(i64, i32)> {
+    let end_time = point.interval.as_ref()?.end_time.as_ref()?;
+    Some((end_time.seconds, end_time.nanos))
+}
+
+fn merge_points(point: &mut Point, other: Point) {
+    if let (Some(interval), Some(other_interval)) = (point.interval.as_mut(), other.interval) {
+        let key = |t: &prost_types::Timestamp| (t.seconds, t.nanos);
+        if let Some(start_time) = other_interval.start_time {
+            if interval.start_time.as_ref().map_or(true, |t| key(&start_time) < key(t)) {
+                interval.start_time = Some(start_time);
+            }
+        }
+        if let Some(end_time) = other_interval.end_time {
+            if interval.end_time.as_ref().map_or(true, |t| key(&end_time) > key(t)) {
+                interval.end_time = Some(end_time);
+            }
+        }
+    }
+    let (Some(value), Some(other_value)) = (
+        point.value.as_mut().and_then(|v| v.value.as_mut()),
+        other.value.and_then(|v| v.value),
+    ) else {
+        return;
+    };
+    match (value, other_value) {
+        (Value::Int64Value(v), Value::Int64Value(o)) => *v += o,
+        (Value::DoubleValue(v), Value::DoubleValue(o)) => *v += o,
+        (Value::DistributionValue(v), Value::DistributionValue(o)) => merge_distributions(v, o),
+        _ => {}
+    }
+}
+
+/// Pools two distributions. Distributions with different buckets are first folded
+/// into their common buckets, see `common_bucket_options`.
+fn merge_distributions(into: &mut Distribution, mut other: Distribution) {
+    if into.bucket_options != other.bucket_options {
+        let options = |d: &Distribution| d.bucket_options.as_ref().and_then(|o| o.options.clone());
+        // `reaggregate` only merges distributions with common buckets
+        let (Some(options), Some(other_options)) = (options(into), options(&other)) else {
+            return;
+        };
+        let Some(common) = common_bucket_options(&options, &other_options) else {
+            return;
+        };
+        let target = bucket_bounds(&common);
+        into.bucket_counts = fold_bucket_counts(&into.bucket_counts, &bucket_bounds(&options), &target);
+        other.bucket_counts = fold_bucket_counts(&other.bucket_counts, &bucket_bounds(&other_options), &target);
+        into.bucket_options =
+            Some(crate::gcloud_sdk::google::api::distribution::BucketOptions { options: Some(common) });
+    }
+    let count = into.count + other.count;
+    if count == 0 {
+        return;
+    }
+    let (n1, n2) = (into.count as f64, other.count as f64);
+    let delta = other.mean - into.mean;
+    into.sum_of_squared_deviation += other.sum_of_squared_deviation + delta * delta * n1 * n2 / count as f64;
+    into.mean += delta * n2 / count as f64;
+    into.count = count;
+    into.range = match (into.range.take(), other.range) {
+        (Some(a), Some(b)) => Some(crate::gcloud_sdk::google::api::distribution::Range {
+            min: a.min.min(b.min),
+            max: a.max.max(b.max),
+        }),
+        (a, b) => a.or(b),
+    };
+    if into.bucket_counts.len() < other.bucket_counts.len() {
+        into.bucket_counts.resize(other.bucket_counts.len(), 0);
+    }
+    for (count, other_count) in into.bucket_counts.iter_mut().zip(other.bucket_counts) {
+        *count += other_count;
+    }
+    into.exemplars.extend(other.exemplars);
+}
+
+fn same_bound(a: f64, b: f64) -> bool {
+    (a - b).abs() <= BOUND_TOLERANCE * a.abs().max(b.abs())
+}
+
+/// Buckets both layouts can be folded into without splitting a bucket: the coarser of
+/// two exponential layouts on the same lattice (e.g. OTel scales 3 and 1), widened to
+/// cover both, else the bounds the layouts share. None when they share no bound.
+fn common_bucket_options(a: &Options, b: &Options) -> Option<Options> {
+    if let (Options::ExponentialBuckets(a), Options::ExponentialBuckets(b)) = (a, b) {
+        if let Some(exponential) = common_exponential_buckets(a, b) {
+            return Some(Options::ExponentialBuckets(exponential));
+        }
+    }
+    let other_bounds = bucket_bounds(b);
+    let bounds = bucket_bounds(a)
+        .into_iter()
+        .filter(|bound| other_bounds.iter().any(|other| same_bound(*bound, *other)))
+        .collect::<Vec<f64>>();
+    (!bounds.is_empty()).then_some(Options::ExplicitBuckets(Explicit { bounds }))
+}
+
+/// Coarser of two exponential layouts whose bounds are powers of the finer growth factor
+fn common_exponential_buckets(a: &Exponential, b: &Exponential) -> Option<Exponential> {
+    let (fine, coarse) = if a.growth_factor <= b.growth_factor {
+        (a, b)
+    } else {
+        (b, a)
+    };
+    let unit = fine.growth_factor.ln();
+    if !(unit > 0.0 && fine.scale > 0.0 && coarse.scale > 0.0) {
+        return None;
+    }
+    // exponent of `value` in powers of the fine growth factor
+    let exponent = |value: f64| {
+        let exponent = value.ln() / unit;
+        ((exponent - exponent.round()).abs() < 1e-6).then_some(exponent.round() as i64)
+    };
+    let step = exponent(coarse.growth_factor)?;
+    let coarse_start = exponent(coarse.scale)?;
+    let fine_start = exponent(fine.scale)?;
+    let fine_end = fine_start + fine.num_finite_buckets as i64;
+    // coarse buckets, relative to the first one of `coarse`, covering both layouts
+    let first = (fine_start - coarse_start).div_euclid(step).min(0);
+    let last = (-(coarse_start - fine_end).div_euclid(step)).max(coarse.num_finite_buckets as i64);
+    if last - first + 2 > MAX_BUCKETS {
+        return None;
+    }
+    Some(Exponential {
+        num_finite_buckets: (last - first) as i32,
+        growth_factor: coarse.growth_factor,
+        scale: coarse.scale * coarse.growth_factor.powi(first as i32),
+    })
+}
+
+/// Counts of the buckets delimited by `bounds` in the coarser buckets delimited by
+/// `target`. The underflow and overflow buckets go to the outermost target buckets
+/// around them.
+fn fold_bucket_counts(counts: &[i64], bounds: &[f64], target: &[f64]) -> Vec<i64> {
+    let mut folded = vec![0; target.len() + 1];
+    for (i, count) in counts.iter().enumerate() {
+        // bucket i starts at bounds[i - 1]
+        let index = match i.checked_sub(1).map(|j| bounds.get(j)) {
+            None => 0,
+            Some(Some(lower)) => target
+                .iter()
+                .take_while(|bound| **bound < *lower || same_bound(**bound, *lower))
+                .count(),
+            Some(None) => target.len(),
+        };
+        folded[index] += count;
+    }
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud_sdk::google::{
+        api::Metric,
+        monitoring::v3::{TimeInterval, TypedValue},
+    };
+
+    fn series(labels: &[(&str, &str)], value: Value, end_seconds: i64) -> TimeSeries {
+        TimeSeries {
+            metric: Some(Metric {
+                r#type: "workload.googleapis.com/m".to_string(),
+                labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            }),
+            points: vec![Point {
+                interval: Some(TimeInterval {
+                    start_time: None,
+                    end_time: Some(prost_types::Timestamp {
+                        seconds: end_seconds,
+                        nanos: 0,
+                    }),
+                }),
+                value: Some(TypedValue { value: Some(value) }),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_label_key() {
+        let filter = AttributeFilter::new(
+            AttributeRules {
+                deny: vec!["user.id".to_string()],
+                rename: HashMap::from([("http.route".to_string(), "route".to_string())]),
+                ..Default::default()
+            },
+            HashMap::from([(
+                "requests".to_string(),
+                AttributeRules {
+                    allow: Some(vec!["http.route".to_string(), "status".to_string()]),
+                    rename: HashMap::from([("status".to_string(), "code".to_string())]),
+                    ..Default::default()
+                },
+            )]),
+        );
+        assert_eq!(filter.label_key("other", "user_id"), None);
+        assert_eq!(filter.label_key("other", "http_route"), Some("route".to_string()));
+        assert_eq!(filter.label_key("other", "host"), Some("host".to_string()));
+        assert_eq!(filter.label_key("requests", "host"), None);
+        assert_eq!(filter.label_key("requests", "http_route"), Some("route".to_string()));
+        assert_eq!(filter.label_key("requests", "status"), Some("code".to_string()));
+    }
+
+    #[test]
+    fn test_reaggregate() {
+        let filter = AttributeFilter::new(
+            AttributeRules {
+                deny: vec!["user_id".to_string()],
+                ..Default::default()
+            },
+            HashMap::new(),
+        );
+        let mut all_series = vec![
+            series(&[("user_id", "1"), ("route", "/a")], Value::Int64Value(2), 10),
+            series(&[("user_id", "2"), ("route", "/a")], Value::Int64Value(3), 11),
+            series(&[("user_id", "1"), ("route", "/b")], Value::Int64Value(1), 10),
+        ];
+        filter.apply("m", &mut all_series);
+        assert_eq!(
+            reaggregate(all_series.clone(), Reaggregation::Sum),
+            vec![
+                series(&[("route", "/a")], Value::Int64Value(5), 11),
+                series(&[("route", "/b")], Value::Int64Value(1), 10),
+            ]
+        );
+        assert_eq!(
+            reaggregate(all_series, Reaggregation::LastValue),
+            vec![
+                series(&[("route", "/a")], Value::Int64Value(3), 11),
+                series(&[("route", "/b")], Value::Int64Value(1), 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_distributions() {
+        let distribution = |values: &[f64]| {
+            let count = values.len() as f64;
+            let mean = values.iter().sum::<f64>() / count;
+            Distribution {
+                count: values.len() as i64,
+                mean,
+                sum_of_squared_deviation: values.iter().map(|v| (v - mean).powi(2)).sum(),
+                bucket_counts: vec![
+                    values.iter().filter(|v| **v <= 5.0).count() as i64,
+                    values.iter().filter(|v| **v > 5.0).count() as i64,
+                ],
+                ..Default::default()
+            }
+        };
+        let mut merged = distribution(&[1.0, 3.0]);
+        merge_distributions(&mut merged, distribution(&[2.0, 4.0, 10.0]));
+        let expected = distribution(&[1.0, 3.0, 2.0, 4.0, 10.0]);
+        assert_eq!(merged.count, 5);
+        assert_eq!(merged.bucket_counts, vec![4, 1]);
+        assert!((merged.mean - expected.mean).abs() < 1e-9);
+        assert!((merged.sum_of_squared_deviation - expected.sum_of_squared_deviation).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_merge_distributions_with_different_buckets() {
+        let distribution = |options: Options, bucket_counts: Vec<i64>| Distribution {
+            count: bucket_counts.iter().sum(),
+            bucket_options: Some(crate::gcloud_sdk::google::api::distribution::BucketOptions {
+                options: Some(options),
+            }),
+            bucket_counts,
+            ..Default::default()
+        };
+        let exponential = |num_finite_buckets: i32, growth_factor: f64, scale: f64| {
+            Options::ExponentialBuckets(Exponential {
+                num_finite_buckets,
+                growth_factor,
+                scale,
+            })
+        };
+
+        // OTel scale 1 (bounds 1, √2, 2, 2√2, 4) downscaled to scale 0 (bounds 2, 4, 8), widened to 1
+        let mut merged = distribution(exponential(4, 2f64.sqrt(), 1.0), vec![0, 1, 2, 3, 4, 0]);
+        merge_distributions(&mut merged, distribution(exponential(2, 2.0, 2.0), vec![0, 5, 6, 0]));
+        assert_eq!(merged.count, 21);
+        assert_eq!(merged.bucket_options.unwrap().options, Some(exponential(3, 2.0, 1.0)));
+        assert_eq!(merged.bucket_counts, vec![0, 3, 12, 6, 0]);
+
+        // explicit buckets are folded into the bounds they share
+        let mut merged = distribution(
+            Options::ExplicitBuckets(Explicit {
+                bounds: vec![0.0, 5.0, 10.0],
+            }),
+            vec![1, 2, 3, 4],
+        );
+        merge_distributions(
+            &mut merged,
+            distribution(
+                Options::ExplicitBuckets(Explicit {
+                    bounds: vec![5.0, 20.0],
+                }),
+                vec![5, 6, 7],
+            ),
+        );
+        assert_eq!(
+            merged.bucket_options.unwrap().options,
+            Some(Options::ExplicitBuckets(Explicit { bounds: vec![5.0] }))
+        );
+        assert_eq!(merged.bucket_counts, vec![8, 20]);
+
+        // distributions without a common bound stay separate series
+        let series_of = |bound: f64, count: i64| {
+            series(
+                &[("route", "/a")],
+                Value::DistributionValue(distribution(
+                    Options::ExplicitBuckets(Explicit { bounds: vec![bound] }),
+                    vec![count, 0],
+                )),
+                10,
+            )
+        };
+        let all_series = vec![series_of(1.0, 2), series_of(2.0, 3)];
+        assert_eq!(reaggregate(all_series.clone(), Reaggregation::Sum), all_series);
+    }
+}
@@ -0,0 +1,181 @@
+use crate::gcloud_sdk::google::{
+    api::{
+        metric_descriptor::{MetricKind, ValueType},
+        MetricDescriptor,
+    },
+    monitoring::v3::{typed_value::Value, TimeSeries},
+};
+use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+use std::fmt::{Display, Formatter};
+
+/// Local metric descriptor that doesn't match the one already stored in Cloud
+/// Monitoring, e.g. after an instrument changed from `u64_counter` to `f64_counter`.
+/// Cloud Monitoring rejects every point written against a conflicting descriptor.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorConflict {
+    /// metric_type: type of the conflicting descriptor.
+    pub metric_type: String,
+    /// existing_kind: metric kind stored in Cloud Monitoring.
+    pub existing_kind: MetricKind,
+    /// local_kind: metric kind produced by the exporter.
+    pub local_kind: MetricKind,
+    /// existing_value_type: value type stored in Cloud Monitoring.
+    pub existing_value_type: ValueType,
+    /// local_value_type: value type produced by the exporter.
+    pub local_value_type: ValueType,
+    /// missing_labels: labels produced by the exporter that the stored descriptor lacks.
+    pub missing_labels: Vec<String>,
+}
+
+/// What to do with a metric whose descriptor conflicts with the existing one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DescriptorConflictResolution {
+    /// Don't export the metric.
+    #[default]
+    SkipMetric,
+    /// Convert the points to the existing value type (INT64 <-> DOUBLE only,
+    /// doubles are rounded). Other conflicts skip the metric.
+    CoerceValueType,
+    /// Write the metric under `<type>_v2` (or the first free `_vN`). A version whose
+    /// existing descriptor conflicts, lacks labels or can't be fetched is passed over.
+    VersionedMetricType,
+}
+
+impl DescriptorConflict {
+    /// Returns the conflict between `existing` and `local`, if their kind or value type differ
+    pub(crate) fn detect(existing: &MetricDescriptor, local: &MetricDescriptor) -> Option<Self> {
+        if existing.metric_kind == local.metric_kind && existing.value_type == local.value_type {
+            return None;
+        }
+        Some(Self {
+            metric_type: local.r#type.clone(),
+            existing_kind: existing.metric_kind(),
+            local_kind: local.metric_kind(),
+            existing_value_type: existing.value_type(),
+            local_value_type: local.value_type(),
+            missing_labels: missing_labels(existing, local),
+        })
+    }
+
+    /// Whether the points can be written against the existing descriptor after conversion
+    pub(crate) fn is_coercible(&self) -> bool {
+        let scalar = |value_type| matches!(value_type, ValueType::Int64 | ValueType::Double);
+        self.existing_kind == self.local_kind && scalar(self.existing_value_type) && scalar(self.local_value_type)
+    }
+}
+
+impl Display for DescriptorConflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "metric descriptor {} exists as {:?}/{:?}, but the exporter produces {:?}/{:?}",
+            self.metric_type, self.existing_kind, self.existing_value_type, self.local_kind, self.local_value_type
+        )
+    }
+}
+
+impl std::error::Error for DescriptorConflict {}
+
+/// Labels of `local` that `existing` lacks
+pub(crate) fn missing_labels(existing: &MetricDescriptor, local: &MetricDescriptor) -> Vec<String> {
+    local
+        .labels
+        .iter()
+        .filter(|label| !existing.labels.iter().any(|l| l.key == label.key))
+        .map(|label| label.key.clone())
+        .collect()
+}
+
+/// Whether the descriptor of `data` was coerced to a scalar value type other
+/// than the one of its points
+pub(crate) fn values_coerced(descriptor: &MetricDescriptor, data: &AggregatedMetrics) -> bool {
+    let points_value_type = match data {
+        AggregatedMetrics::F64(MetricData::Sum(_) | MetricData::Gauge(_)) => ValueType::Double,
+        AggregatedMetrics::I64(MetricData::Sum(_) | MetricData::Gauge(_))
+        | AggregatedMetrics::U64(MetricData::Sum(_) | MetricData::Gauge(_)) => ValueType::Int64,
+        _ => return false,
+    };
+    descriptor.value_type() != points_value_type
+}
+
+/// Converts INT64 and DOUBLE point values to the value type of their series
+pub(crate) fn coerce_values(series: &mut [TimeSeries]) {
+    for ts in series {
+        let value_type = ts.value_type();
+        for value in ts.points.iter_mut().filter_map(|p| p.value.as_mut()?.value.as_mut()) {
+            match (value_type, &value) {
+                (ValueType::Double, Value::Int64Value(v)) => *value = Value::DoubleValue(*v as f64),
+                (ValueType::Int64, Value::DoubleValue(v)) => *value = Value::Int64Value(v.round() as i64),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud_sdk::google::monitoring::v3::{Point, TypedValue};
+
+    fn descriptor(metric_kind: MetricKind, value_type: ValueType) -> MetricDescriptor {
+        MetricDescriptor {
+            r#type: "workload.googleapis.com/mycounter".to_string(),
+            metric_kind: metric_kind.into(),
+            value_type: value_type.into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_detect() {
+        let existing = descriptor(MetricKind::Cumulative, ValueType::Int64);
+        assert_eq!(
+            DescriptorConflict::detect(&existing, &descriptor(MetricKind::Cumulative, ValueType::Int64)),
+            None
+        );
+        let conflict = DescriptorConflict::detect(&existing, &descriptor(MetricKind::Gauge, ValueType::Double));
+        assert_eq!(
+            conflict,
+            Some(DescriptorConflict {
+                metric_type: "workload.googleapis.com/mycounter".to_string(),
+                existing_kind: MetricKind::Cumulative,
+                local_kind: MetricKind::Gauge,
+                existing_value_type: ValueType::Int64,
+                local_value_type: ValueType::Double,
+                missing_labels: vec![],
+            })
+        );
+        assert!(!conflict.unwrap().is_coercible());
+        let conflict = DescriptorConflict::detect(&existing, &descriptor(MetricKind::Cumulative, ValueType::Double));
+        assert!(conflict.unwrap().is_coercible());
+        let conflict =
+            DescriptorConflict::detect(&existing, &descriptor(MetricKind::Cumulative, ValueType::Distribution));
+        assert!(!conflict.unwrap().is_coercible());
+    }
+
+    #[test]
+    fn test_coerce_values() {
+        let series = |value_type: ValueType, value: Value| TimeSeries {
+            value_type: value_type.into(),
+            points: vec![Point {
+                interval: None,
+                value: Some(TypedValue { value: Some(value) }),
+            }],
+            ..Default::default()
+        };
+        let mut all_series = vec![
+            series(ValueType::Double, Value::Int64Value(3)),
+            series(ValueType::Int64, Value::DoubleValue(2.6)),
+            series(ValueType::Int64, Value::Int64Value(1)),
+        ];
+        coerce_values(&mut all_series);
+        assert_eq!(
+            all_series,
+            vec![
+                series(ValueType::Double, Value::DoubleValue(3.0)),
+                series(ValueType::Int64, Value::Int64Value(3)),
+                series(ValueType::Int64, Value::Int64Value(1)),
+            ]
+        );
+    }
+}
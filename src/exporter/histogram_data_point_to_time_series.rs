@@ -1,10 +1,22 @@
 use super::{utils::kv_map_normalize_k_v, UNIQUE_IDENTIFIER_KEY};
 use crate::exporter::to_f64::ToF64;
 use crate::gcloud_sdk;
-use gcloud_sdk::google::{api::MetricDescriptor, monitoring::v3::TimeSeries};
+use gcloud_sdk::google::{
+    api::{
+        distribution::{bucket_options::Options, Exemplar, Range},
+        Distribution, MetricDescriptor,
+    },
+    monitoring::v3::{typed_value::Value, DroppedLabels, SpanContext, TimeSeries, TypedValue},
+};
+use opentelemetry::KeyValue;
 use opentelemetry_sdk::metrics::data;
+use prost::Message;
 use std::time::SystemTime;
 
+const SPAN_CONTEXT_TYPE_URL: &str = "type.googleapis.com/google.monitoring.v3.SpanContext";
+const DROPPED_LABELS_TYPE_URL: &str = "type.googleapis.com/google.monitoring.v3.DroppedLabels";
+
+#[allow(clippy::too_many_arguments)]
 pub fn convert<T: ToF64 + Copy>(
     data_point: &data::HistogramDataPoint<T>,
     start_time: &SystemTime,
@@ -13,6 +25,7 @@ pub fn convert<T: ToF64 + Copy>(
     monitored_resource_data: &Option<gcloud_sdk::google::api::MonitoredResource>,
     add_unique_identifier: bool,
     unique_identifier: &str,
+    project_id: &str,
 ) -> TimeSeries {
     let data_point_start_time = start_time
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -49,9 +62,9 @@ pub fn convert<T: ToF64 + Copy>(
                         bounds: data_point.bounds().collect::<Vec<f64>>(),
                     })),
                 }),
-                range: None,
+                range: range(data_point.count(), data_point.min(), data_point.max()),
                 bucket_counts: data_point.bucket_counts().map(|v| v as i64).collect(),
-                exemplars: Default::default(),
+                exemplars: convert_exemplars(data_point.exemplars(), project_id),
             })),
         }),
     };
@@ -82,22 +95,47 @@ pub fn convert<T: ToF64 + Copy>(
     time_series
 }
 
-#[cfg(feature = "")]
+/// Converts an OpenTelemetry base-2 exponential histogram point to a
+/// Cloud Monitoring distribution.
+///
+/// See reference impl
+///
+/// https://github.com/GoogleCloudPlatform/opentelemetry-operations-go/blob/main/exporter/metric/metric.go
+///
+/// Cloud Monitoring exponential buckets cannot represent negative values, so
+/// the zero bucket and every negative bucket are folded into the underflow
+/// bucket. The overflow bucket is empty unless the bucket bounds overflow f64,
+/// see `exponential_buckets`.
+#[allow(clippy::too_many_arguments)]
 pub fn convert_exponential<T: ToF64 + Copy>(
     data_point: &data::ExponentialHistogramDataPoint<T>,
+    start_time: &SystemTime,
+    time: &SystemTime,
     descriptor: &MetricDescriptor,
     monitored_resource_data: &Option<gcloud_sdk::google::api::MonitoredResource>,
+    add_unique_identifier: bool,
+    unique_identifier: &str,
+    project_id: &str,
 ) -> TimeSeries {
-    let data_point_start_time = data_point
-        .start_time
+    let data_point_start_time = start_time
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_nanos();
-    let data_point_time = data_point
-        .time
+    let data_point_time = time
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_nanos();
+
+    let underflow = data_point.zero_count() + data_point.negative_bucket().counts().sum::<u64>();
+    let positive_counts = data_point.positive_bucket().counts().collect::<Vec<u64>>();
+    let (options, bucket_counts) = exponential_buckets(
+        data_point.scale(),
+        data_point.positive_bucket().offset(),
+        underflow,
+        &positive_counts,
+    );
+
+    let sum = data_point.sum().to_f64();
     let point = gcloud_sdk::google::monitoring::v3::Point {
         interval: Some(gcloud_sdk::google::monitoring::v3::TimeInterval {
             start_time: Some(gcloud_sdk::prost_types::Timestamp {
@@ -111,52 +149,408 @@ pub fn convert_exponential<T: ToF64 + Copy>(
         }),
         value: Some(gcloud_sdk::google::monitoring::v3::TypedValue {
             value: Some(gcloud_sdk::google::monitoring::v3::typed_value::Value::DistributionValue(gcloud_sdk::google::api::Distribution {
-                count: data_point.count as i64,
+                count: data_point.count() as i64,
                 mean: {
-                    if data_point.count == 0 {
+                    if data_point.count() == 0 || sum.is_nan() {
                         0.0
                     } else {
-                        data_point.sum.to_f64() / data_point.count as f64
+                        sum / data_point.count() as f64
                     }
                 },
                 sum_of_squared_deviation: 0.0,
                 bucket_options: Some(gcloud_sdk::google::api::distribution::BucketOptions {
-                    options: Some(gcloud_sdk::google::api::distribution::bucket_options::Options::ExponentialBuckets(gcloud_sdk::google::api::distribution::bucket_options::Exponential {
-                        bounds: data_point.bounds.clone(),
-                    })),
+                    options: Some(options),
                 }),
-                range: None,
-                bucket_counts: data_point.bucket_counts.iter().map(|v| *v as i64).collect(),
-                exemplars: Default::default(),
+                range: range(data_point.count() as u64, data_point.min(), data_point.max()),
+                bucket_counts,
+                exemplars: convert_exemplars(data_point.exemplars(), project_id),
             })),
         }),
     };
 
-    let labels = data_point
-        .attributes
-        .iter()
-        .map(|kv| {
-            (
-                normalize_label_key(&kv.key.to_string()),
-                kv.value.to_string(),
-            )
-        })
+    let mut labels = data_point
+        .attributes()
+        .map(kv_map_normalize_k_v)
         .collect::<std::collections::HashMap<String, String>>();
-    // if self.add_unique_identifier {
-    //     labels.insert(UNIQUE_IDENTIFIER_KEY.to_string(), self.unique_identifier.clone());
-    // }
+    if add_unique_identifier {
+        labels.insert(
+            UNIQUE_IDENTIFIER_KEY.to_string(),
+            unique_identifier.to_string(),
+        );
+    }
 
-    let time_series = TimeSeries {
+    TimeSeries {
         resource: monitored_resource_data.clone(),
         metadata: None,
         metric_kind: descriptor.metric_kind,
         value_type: descriptor.value_type,
         metric: Some(gcloud_sdk::google::api::Metric {
             r#type: descriptor.r#type.clone(),
-            labels: labels,
+            labels,
         }),
         points: vec![point],
         unit: descriptor.unit.clone(),
+    }
+}
+
+/// Bucket layout and counts of the positive buckets of an exponential histogram,
+/// with the underflow bucket first and the overflow bucket last.
+///
+/// When the bounds of the layout overflow f64 (coarse scales such as -10, where
+/// the growth factor is 2^1024) explicit bounds are sent instead: buckets whose
+/// lower bound underflows to 0 are folded into the underflow bucket and buckets
+/// whose lower bound is infinite into the last finite one.
+fn exponential_buckets(scale: i8, offset: i32, underflow: u64, positive_counts: &[u64]) -> (Options, Vec<i64>) {
+    if positive_counts.is_empty() {
+        // an exponential layout needs at least one finite bucket, so send a
+        // plain underflow/overflow layout split at zero instead
+        return (
+            Options::ExplicitBuckets(gcloud_sdk::google::api::distribution::bucket_options::Explicit {
+                bounds: vec![0.0],
+            }),
+            vec![underflow as i64, 0],
+        );
+    }
+    let bound = |index: i32| 2f64.powf(index as f64 * 2f64.powi(-(scale as i32)));
+    let num_finite_buckets = positive_counts.len() as i32;
+    let growth_factor = 2f64.powf(2f64.powi(-(scale as i32)));
+    let first_bound = growth_factor.powi(offset);
+    if first_bound > 0.0 && (first_bound * growth_factor.powi(num_finite_buckets)).is_finite() {
+        let mut bucket_counts = Vec::with_capacity(positive_counts.len() + 2);
+        bucket_counts.push(underflow as i64);
+        bucket_counts.extend(positive_counts.iter().map(|v| *v as i64));
+        bucket_counts.push(0);
+        return (
+            Options::ExponentialBuckets(gcloud_sdk::google::api::distribution::bucket_options::Exponential {
+                num_finite_buckets,
+                growth_factor,
+                scale: first_bound,
+            }),
+            bucket_counts,
+        );
+    }
+
+    let mut bounds = Vec::new();
+    let mut bucket_counts = vec![underflow as i64];
+    for (i, count) in positive_counts.iter().enumerate() {
+        let lower = bound(offset + i as i32);
+        if lower > 0.0 && lower.is_finite() {
+            bounds.push(lower);
+            bucket_counts.push(0);
+        }
+        *bucket_counts.last_mut().unwrap() += *count as i64;
+    }
+    let upper = bound(offset + num_finite_buckets);
+    if upper.is_finite() || bounds.is_empty() {
+        bounds.push(if upper.is_finite() { upper } else { 0.0 });
+        bucket_counts.push(0);
+    }
+    (
+        Options::ExplicitBuckets(gcloud_sdk::google::api::distribution::bucket_options::Explicit { bounds }),
+        bucket_counts,
+    )
+}
+
+/// Range of a histogram, None when it holds no value
+fn range<T: ToF64 + Copy>(count: u64, min: Option<T>, max: Option<T>) -> Option<Range> {
+    if count == 0 {
+        return None;
+    }
+    Some(Range {
+        min: min?.to_f64(),
+        max: max?.to_f64(),
+    })
+}
+
+/// Bounds between the buckets of a distribution, in increasing order
+pub(crate) fn bucket_bounds(options: &Options) -> Vec<f64> {
+    match options {
+        Options::ExplicitBuckets(explicit) => explicit.bounds.clone(),
+        Options::ExponentialBuckets(exponential) => (0..=exponential.num_finite_buckets)
+            .map(|i| exponential.scale * exponential.growth_factor.powi(i))
+            .collect(),
+        Options::LinearBuckets(linear) => (0..=linear.num_finite_buckets)
+            .map(|i| linear.offset + linear.width * i as f64)
+            .collect(),
+    }
+}
+
+/// Estimates the sum of squared deviation of a distribution, assuming every
+/// value sits at the midpoint of its bucket. The unbounded underflow and
+/// overflow buckets are closed with the distribution range when known.
+pub(crate) fn estimate_sum_of_squared_deviation(distribution: &Distribution) -> f64 {
+    let Some(options) = distribution
+        .bucket_options
+        .as_ref()
+        .and_then(|o| o.options.as_ref())
+    else {
+        return 0.0;
     };
-    time_series
+    let bounds = bucket_bounds(options);
+    let (Some(first), Some(last)) = (bounds.first(), bounds.last()) else {
+        return 0.0;
+    };
+    let min = distribution.range.as_ref().map(|r| r.min);
+    let max = distribution.range.as_ref().map(|r| r.max);
+    distribution
+        .bucket_counts
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(i, count)| {
+            let lower = if i == 0 {
+                min.unwrap_or(*first).min(*first)
+            } else {
+                bounds.get(i - 1).copied().unwrap_or(*last)
+            };
+            let upper = bounds.get(i).copied().unwrap_or(max.unwrap_or(*last).max(*last));
+            let mut midpoint = (lower + upper) / 2.0;
+            if let (Some(min), Some(max)) = (min, max) {
+                midpoint = midpoint.clamp(min, max);
+            }
+            *count as f64 * (midpoint - distribution.mean).powi(2)
+        })
+        .sum()
+}
+
+/// Fills the sum of squared deviation of every distribution point
+pub(crate) fn estimate_sums_of_squared_deviation(series: &mut [TimeSeries]) {
+    for ts in series {
+        for point in ts.points.iter_mut() {
+            if let Some(TypedValue {
+                value: Some(Value::DistributionValue(distribution)),
+            }) = point.value.as_mut()
+            {
+                distribution.sum_of_squared_deviation = estimate_sum_of_squared_deviation(distribution);
+            }
+        }
+    }
+}
+
+fn convert_exemplars<'a, T: ToF64 + Copy + 'a>(
+    exemplars: impl Iterator<Item = &'a data::Exemplar<T>>,
+    project_id: &str,
+) -> Vec<Exemplar> {
+    exemplars
+        .map(|exemplar| {
+            convert_exemplar(
+                exemplar.value.to_f64(),
+                exemplar.time(),
+                exemplar.trace_id(),
+                exemplar.span_id(),
+                exemplar.filtered_attributes(),
+                project_id,
+            )
+        })
+        .collect()
+}
+
+/// Builds a distribution exemplar. The sampled span is attached as a
+/// `SpanContext` so Cloud Monitoring can link to Cloud Trace, the attributes
+/// filtered out of the time series are attached as `DroppedLabels`.
+fn convert_exemplar<'a>(
+    value: f64,
+    time: SystemTime,
+    trace_id: &[u8; 16],
+    span_id: &[u8; 8],
+    filtered_attributes: impl Iterator<Item = &'a KeyValue>,
+    project_id: &str,
+) -> Exemplar {
+    let exemplar_time = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut attachments = Vec::new();
+    if trace_id.iter().any(|b| *b != 0) && span_id.iter().any(|b| *b != 0) {
+        let span_context = SpanContext {
+            span_name: format!(
+                "projects/{}/traces/{}/spans/{}",
+                project_id,
+                to_hex(trace_id),
+                to_hex(span_id)
+            ),
+        };
+        attachments.push(gcloud_sdk::prost_types::Any {
+            type_url: SPAN_CONTEXT_TYPE_URL.to_string(),
+            value: span_context.encode_to_vec(),
+        });
+    }
+    let dropped_labels = DroppedLabels {
+        label: filtered_attributes.map(kv_map_normalize_k_v).collect(),
+    };
+    if !dropped_labels.label.is_empty() {
+        attachments.push(gcloud_sdk::prost_types::Any {
+            type_url: DROPPED_LABELS_TYPE_URL.to_string(),
+            value: dropped_labels.encode_to_vec(),
+        });
+    }
+    Exemplar {
+        value,
+        timestamp: Some(gcloud_sdk::prost_types::Timestamp {
+            seconds: (exemplar_time / 1_000_000_000) as i64,
+            nanos: (exemplar_time % 1_000_000_000) as i32,
+        }),
+        attachments,
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_exponential_buckets() {
+        use gcloud_sdk::google::api::distribution::bucket_options::{Explicit, Exponential};
+
+        assert_eq!(
+            exponential_buckets(0, -1, 1, &[1, 1, 2, 4]),
+            (
+                Options::ExponentialBuckets(Exponential {
+                    num_finite_buckets: 4,
+                    growth_factor: 2.0,
+                    scale: 0.5,
+                }),
+                vec![1, 1, 1, 2, 4, 0]
+            )
+        );
+        assert_eq!(
+            exponential_buckets(0, 0, 3, &[]),
+            (Options::ExplicitBuckets(Explicit { bounds: vec![0.0] }), vec![3, 0])
+        );
+        // at scale -10 the growth factor 2^1024 overflows: bucket -1 is (2^-1024, 1],
+        // bucket 0 is (1, 2^1024] and becomes the overflow bucket
+        assert_eq!(
+            exponential_buckets(-10, -1, 1, &[2, 3]),
+            (
+                Options::ExplicitBuckets(Explicit {
+                    bounds: vec![2f64.powf(-1024.0), 1.0],
+                }),
+                vec![1, 2, 3]
+            )
+        );
+        // bucket -2 starts at 2^-2048 which underflows to 0
+        assert_eq!(
+            exponential_buckets(-10, -2, 1, &[4, 2]),
+            (
+                Options::ExplicitBuckets(Explicit {
+                    bounds: vec![2f64.powf(-1024.0), 1.0],
+                }),
+                vec![5, 2, 0]
+            )
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        assert_eq!(range(2, Some(1u64), Some(5u64)), Some(Range { min: 1.0, max: 5.0 }));
+        assert_eq!(range(2, None::<f64>, Some(5.0)), None);
+        assert_eq!(range(0, Some(0.0), Some(0.0)), None);
+    }
+
+    #[test]
+    fn test_estimate_sum_of_squared_deviation() {
+        let explicit = |bounds: Vec<f64>| {
+            Some(gcloud_sdk::google::api::distribution::BucketOptions {
+                options: Some(Options::ExplicitBuckets(
+                    gcloud_sdk::google::api::distribution::bucket_options::Explicit { bounds },
+                )),
+            })
+        };
+        // values 1, 3, 3, 5 in buckets (-inf, 2], (2, 4], (4, inf)
+        let distribution = Distribution {
+            count: 4,
+            mean: 3.0,
+            range: Some(Range { min: 1.0, max: 5.0 }),
+            bucket_options: explicit(vec![2.0, 4.0]),
+            bucket_counts: vec![1, 2, 1],
+            ..Default::default()
+        };
+        // midpoints 1.5, 3.0, 4.5
+        assert_eq!(estimate_sum_of_squared_deviation(&distribution), 4.5);
+
+        // values 1.5, 3, 3 in buckets [1, 2), [2, 4)
+        let distribution = Distribution {
+            count: 3,
+            mean: 2.5,
+            range: None,
+            bucket_options: Some(gcloud_sdk::google::api::distribution::BucketOptions {
+                options: Some(Options::ExponentialBuckets(
+                    gcloud_sdk::google::api::distribution::bucket_options::Exponential {
+                        num_finite_buckets: 2,
+                        growth_factor: 2.0,
+                        scale: 1.0,
+                    },
+                )),
+            }),
+            // buckets (-inf, 1), [1, 2), [2, 4), [4, inf)
+            bucket_counts: vec![0, 1, 2, 0],
+            ..Default::default()
+        };
+        // midpoints 1.5, 3.0
+        assert_eq!(estimate_sum_of_squared_deviation(&distribution), 1.5);
+
+        let distribution = Distribution {
+            bucket_options: None,
+            ..Default::default()
+        };
+        assert_eq!(estimate_sum_of_squared_deviation(&distribution), 0.0);
+    }
+
+    #[test]
+    fn test_convert_exemplar() {
+        let trace_id = [
+            0x4b, 0xf9, 0x2f, 0x35, 0x77, 0xb3, 0x4d, 0xa6, 0xa3, 0xce, 0x92, 0x9d, 0x0e, 0x0e, 0x47, 0x36,
+        ];
+        let span_id = [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7];
+        let attributes = [KeyValue::new("user.id", "42")];
+        let exemplar = convert_exemplar(
+            12.5,
+            SystemTime::UNIX_EPOCH + Duration::from_millis(1_500),
+            &trace_id,
+            &span_id,
+            attributes.iter(),
+            "fake_project_id",
+        );
+        assert_eq!(exemplar.value, 12.5);
+        assert_eq!(
+            exemplar.timestamp,
+            Some(gcloud_sdk::prost_types::Timestamp {
+                seconds: 1,
+                nanos: 500_000_000,
+            })
+        );
+        assert_eq!(exemplar.attachments.len(), 2);
+        assert_eq!(exemplar.attachments[0].type_url, SPAN_CONTEXT_TYPE_URL);
+        assert_eq!(
+            SpanContext::decode(exemplar.attachments[0].value.as_slice())
+                .unwrap()
+                .span_name,
+            "projects/fake_project_id/traces/4bf92f3577b34da6a3ce929d0e0e4736/spans/00f067aa0ba902b7"
+        );
+        assert_eq!(exemplar.attachments[1].type_url, DROPPED_LABELS_TYPE_URL);
+        assert_eq!(
+            DroppedLabels::decode(exemplar.attachments[1].value.as_slice())
+                .unwrap()
+                .label,
+            std::collections::HashMap::from([("user_id".to_string(), "42".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_convert_exemplar_without_span() {
+        let exemplar = convert_exemplar(
+            1.0,
+            SystemTime::UNIX_EPOCH,
+            &[0; 16],
+            &[0; 8],
+            [].iter(),
+            "fake_project_id",
+        );
+        assert!(exemplar.attachments.is_empty());
+    }
 }
@@ -0,0 +1,138 @@
+use std::{
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+#[cfg(feature = "tokio")]
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+/// Counts the exports currently talking to Cloud Monitoring so that
+/// `force_flush` and `shutdown_with_timeout` can wait for them.
+#[derive(Debug, Default)]
+pub(crate) struct InFlightExports {
+    count: Mutex<usize>,
+    drained: Condvar,
+}
+
+/// Marks one export as in flight until dropped, including when the export
+/// future is cancelled.
+pub(crate) struct InFlightGuard<'a>(&'a InFlightExports);
+
+/// Outcome of `InFlightExports::wait_drained`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Drain {
+    /// No export is in flight.
+    Drained,
+    /// Exports still in flight when the timeout elapsed.
+    TimedOut(usize),
+    /// Exports in flight on a runtime the caller can't block (a current-thread
+    /// runtime, or any runtime without the "tokio-multi-thread" feature), they
+    /// can only make progress once the caller returns.
+    InBackground(usize),
+}
+
+impl InFlightExports {
+    pub(crate) fn start(&self) -> InFlightGuard<'_> {
+        *self.count.lock().unwrap() += 1;
+        InFlightGuard(self)
+    }
+
+    /// Waits until no export is in flight or `timeout` elapsed.
+    ///
+    /// The callers are sync, so the thread is blocked. On a multi-thread runtime
+    /// the worker first hands its other tasks over (`block_in_place`, with the
+    /// "tokio-multi-thread" feature) so that the in-flight exports keep running.
+    /// Other runtimes can't run them while blocked, so there it doesn't wait.
+    pub(crate) fn wait_drained(&self, timeout: Duration) -> Drain {
+        #[cfg(feature = "tokio")]
+        match Handle::try_current().map(|handle| handle.runtime_flavor()) {
+            #[cfg(feature = "tokio-multi-thread")]
+            Ok(RuntimeFlavor::MultiThread) => return tokio::task::block_in_place(|| self.wait_blocking(timeout)),
+            Ok(_) => {
+                return match *self.count.lock().unwrap() {
+                    0 => Drain::Drained,
+                    count => Drain::InBackground(count),
+                };
+            }
+            Err(_) => {}
+        }
+        self.wait_blocking(timeout)
+    }
+
+    fn wait_blocking(&self, timeout: Duration) -> Drain {
+        let deadline = Instant::now() + timeout;
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            let time_left = deadline.saturating_duration_since(Instant::now());
+            if time_left.is_zero() {
+                break;
+            }
+            count = self.drained.wait_timeout(count, time_left).unwrap().0;
+        }
+        match *count {
+            0 => Drain::Drained,
+            count => Drain::TimedOut(count),
+        }
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut count = self.0.count.lock().unwrap();
+        *count -= 1;
+        if *count == 0 {
+            self.0.drained.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_sdk::{error::OTelSdkError, metrics::exporter::PushMetricExporter};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_wait_drained() {
+        let in_flight = Arc::new(InFlightExports::default());
+        assert_eq!(in_flight.wait_drained(Duration::from_millis(10)), Drain::Drained);
+
+        let guard = in_flight.start();
+        assert_eq!(in_flight.wait_drained(Duration::ZERO), Drain::TimedOut(1));
+        assert_eq!(in_flight.wait_drained(Duration::from_millis(10)), Drain::TimedOut(1));
+        drop(guard);
+
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let worker = {
+            let in_flight = in_flight.clone();
+            std::thread::spawn(move || {
+                let _guard = in_flight.start();
+                started_tx.send(()).unwrap();
+                std::thread::sleep(Duration::from_millis(50));
+            })
+        };
+        started_rx.recv().unwrap();
+        assert_eq!(in_flight.wait_drained(Duration::from_secs(5)), Drain::Drained);
+        worker.join().unwrap();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_wait_drained_current_thread() {
+        let in_flight = InFlightExports::default();
+        let guard = in_flight.start();
+        let started = Instant::now();
+        assert_eq!(in_flight.wait_drained(Duration::from_secs(5)), Drain::InBackground(1));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        drop(guard);
+
+        // shutdown can't wait, it reports the exports that may be lost
+        let exporter = crate::GCPMetricsExporter::fake_new();
+        let _guard = exporter.in_flight.start();
+        let started = Instant::now();
+        assert!(matches!(
+            exporter.shutdown_with_timeout(Duration::from_secs(5)),
+            Err(OTelSdkError::InternalFailure(_))
+        ));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(matches!(exporter.force_flush(), Err(OTelSdkError::AlreadyShutdown)));
+    }
+}
@@ -0,0 +1,427 @@
+use super::ExporterStatsHandle;
+use crate::gcloud_sdk::google::{
+    api::{LabelDescriptor, MetricDescriptor},
+    monitoring::v3::TimeSeries,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Mutex,
+};
+
+/// Label holding the `key=value` pairs merged by `ExcessLabelsPolicy::Merge`
+pub(crate) const EXTRA_LABELS_KEY: &str = "extra_labels";
+
+/// Cloud Monitoring limits enforced before descriptors and points are sent,
+/// see https://cloud.google.com/monitoring/quotas#custom_metrics_quotas
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GcmLimits {
+    /// max_metric_type_length: longer metric types are shortened with a hash suffix.
+    pub max_metric_type_length: usize,
+    /// max_label_key_length: longer label keys are shortened with a hash suffix.
+    pub max_label_key_length: usize,
+    /// max_label_value_length: maximum label value size in bytes.
+    pub max_label_value_length: usize,
+    /// max_labels: maximum number of labels of a metric descriptor.
+    pub max_labels: usize,
+    /// long_label_value_policy: what to do with label values over `max_label_value_length`.
+    pub long_label_value_policy: LongLabelValuePolicy,
+    /// excess_labels_policy: what to do with the labels over `max_labels`.
+    pub excess_labels_policy: ExcessLabelsPolicy,
+}
+
+impl Default for GcmLimits {
+    fn default() -> Self {
+        Self {
+            max_metric_type_length: 200,
+            max_label_key_length: 100,
+            max_label_value_length: 1024,
+            max_labels: 30,
+            long_label_value_policy: LongLabelValuePolicy::Truncate,
+            excess_labels_policy: ExcessLabelsPolicy::Drop,
+        }
+    }
+}
+
+/// What to do with a label value over the Cloud Monitoring limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LongLabelValuePolicy {
+    /// Cut the value at the limit.
+    #[default]
+    Truncate,
+    /// Cut the value and end it with a hash of the full value, so that
+    /// values sharing a long prefix stay distinct series.
+    Hash,
+}
+
+/// What to do with the labels of a metric over the Cloud Monitoring limit.
+/// The labels kept are the first ones in alphabetical order.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExcessLabelsPolicy {
+    /// Drop the excess labels.
+    #[default]
+    Drop,
+    /// Keep `max_labels - 1` labels and merge the other ones into the
+    /// `extra_labels` label as `key=value` pairs.
+    Merge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Alteration {
+    MetricTypeSanitized,
+    MetricTypeShortened,
+    LabelKeyShortened,
+    LabelValueTruncated,
+    LabelValueHashed,
+    LabelsDropped,
+    LabelsMerged,
+}
+
+/// Applies `GcmLimits` to descriptors and series, counting the altered labels
+/// in the exporter stats and reporting every alteration once per metric type.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    limits: GcmLimits,
+    stats: ExporterStatsHandle,
+    reported: Mutex<HashSet<(String, Alteration)>>,
+}
+
+impl Limiter {
+    pub(crate) fn new(limits: GcmLimits, stats: ExporterStatsHandle) -> Self {
+        Self {
+            limits,
+            stats,
+            reported: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn report(&self, metric_type: &str, alteration: Alteration, details: &str) {
+        if self
+            .reported
+            .lock()
+            .unwrap()
+            .insert((metric_type.to_string(), alteration))
+        {
+            tracing::warn!(
+                metric_type = metric_type,
+                alteration = ?alteration,
+                details = details,
+                "GCPMetricsExporter: metric altered to fit Cloud Monitoring limits"
+            );
+        }
+    }
+
+    /// Replaces the characters Cloud Monitoring doesn't accept and shortens the type
+    pub(crate) fn metric_type(&self, metric_type: String) -> String {
+        let sanitized = metric_type
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '/') {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect::<String>();
+        if sanitized != metric_type {
+            self.report(&metric_type, Alteration::MetricTypeSanitized, &sanitized);
+        }
+        let limited = shorten(&sanitized, self.limits.max_metric_type_length);
+        if limited != sanitized {
+            self.report(&metric_type, Alteration::MetricTypeShortened, &limited);
+        }
+        limited
+    }
+
+    fn label_key(&self, key: String) -> String {
+        if key.len() <= self.limits.max_label_key_length {
+            return key;
+        }
+        shorten(&key, self.limits.max_label_key_length)
+    }
+
+    /// Number of regular labels a descriptor can hold
+    fn capacity(&self) -> usize {
+        match self.limits.excess_labels_policy {
+            ExcessLabelsPolicy::Drop => self.limits.max_labels,
+            ExcessLabelsPolicy::Merge => self.limits.max_labels.saturating_sub(1),
+        }
+    }
+
+    fn is_full(&self, descriptor: &MetricDescriptor) -> bool {
+        descriptor.labels.iter().filter(|l| l.key != EXTRA_LABELS_KEY).count() >= self.capacity()
+    }
+
+    /// Adds the labels `keys` to the descriptor while it has room for them.
+    /// Returns the number of labels added.
+    pub(crate) fn add_labels(
+        &self,
+        descriptor: &mut MetricDescriptor,
+        keys: impl IntoIterator<Item = String>,
+    ) -> usize {
+        let before = descriptor.labels.len();
+        let mut excess = Vec::new();
+        let mut merged = false;
+        for key in keys {
+            let key = self.label_key(key);
+            if key == EXTRA_LABELS_KEY {
+                merged = true;
+                continue;
+            }
+            if descriptor.labels.iter().any(|l| l.key == key) {
+                continue;
+            }
+            if self.is_full(descriptor) {
+                excess.push(key);
+                continue;
+            }
+            descriptor.labels.push(LabelDescriptor {
+                key,
+                ..Default::default()
+            });
+        }
+        let merge = self.limits.excess_labels_policy == ExcessLabelsPolicy::Merge;
+        if merge && (merged || !excess.is_empty()) && !descriptor.labels.iter().any(|l| l.key == EXTRA_LABELS_KEY) {
+            descriptor.labels.push(LabelDescriptor {
+                key: EXTRA_LABELS_KEY.to_string(),
+                ..Default::default()
+            });
+        }
+        if !excess.is_empty() {
+            let alteration = if merge {
+                Alteration::LabelsMerged
+            } else {
+                Alteration::LabelsDropped
+            };
+            self.report(&descriptor.r#type, alteration, &excess.join(","));
+        }
+        descriptor.labels.len() - before
+    }
+
+    /// Makes the labels of the series of a metric fit the limits and its
+    /// descriptor. Returns whether a label was altered.
+    pub(crate) fn limit_series(&self, descriptor: &MetricDescriptor, series: &mut [TimeSeries]) -> bool {
+        let full = self.is_full(descriptor);
+        let mut altered = false;
+        for ts in series {
+            let Some(metric) = ts.metric.as_mut() else {
+                continue;
+            };
+            let mut labels = BTreeMap::new();
+            let mut excess = BTreeMap::new();
+            for (key, value) in metric.labels.drain() {
+                let limited_key = self.label_key(key.clone());
+                if limited_key != key {
+                    altered = true;
+                    self.record(&descriptor.r#type, Alteration::LabelKeyShortened, &key);
+                }
+                if full && !descriptor.labels.iter().any(|l| l.key == limited_key) {
+                    excess.insert(limited_key, value);
+                } else {
+                    labels.insert(limited_key, value);
+                }
+            }
+            if !excess.is_empty() {
+                altered = true;
+                let details = excess.keys().cloned().collect::<Vec<String>>().join(",");
+                match self.limits.excess_labels_policy {
+                    ExcessLabelsPolicy::Drop => {
+                        self.record_many(&descriptor.r#type, Alteration::LabelsDropped, &details, excess.len());
+                    }
+                    ExcessLabelsPolicy::Merge => {
+                        self.record_many(&descriptor.r#type, Alteration::LabelsMerged, &details, excess.len());
+                        let merged = excess
+                            .iter()
+                            .map(|(k, v)| format!("{}={}", k, v))
+                            .collect::<Vec<String>>()
+                            .join(",");
+                        labels.insert(EXTRA_LABELS_KEY.to_string(), merged);
+                    }
+                }
+            }
+            for (key, value) in labels.iter_mut() {
+                if value.len() <= self.limits.max_label_value_length {
+                    continue;
+                }
+                altered = true;
+                *value = match self.limits.long_label_value_policy {
+                    LongLabelValuePolicy::Truncate => {
+                        self.record(&descriptor.r#type, Alteration::LabelValueTruncated, key);
+                        truncate(value, self.limits.max_label_value_length).to_string()
+                    }
+                    LongLabelValuePolicy::Hash => {
+                        self.record(&descriptor.r#type, Alteration::LabelValueHashed, key);
+                        shorten(value, self.limits.max_label_value_length)
+                    }
+                };
+            }
+            metric.labels = labels.into_iter().collect();
+        }
+        altered
+    }
+
+    fn record(&self, metric_type: &str, alteration: Alteration, details: &str) {
+        self.record_many(metric_type, alteration, details, 1);
+    }
+
+    fn record_many(&self, metric_type: &str, alteration: Alteration, details: &str, count: usize) {
+        self.stats
+            .record_label_alteration(&format!("{:?}", alteration), count as u64);
+        self.report(metric_type, alteration, details);
+    }
+}
+
+/// Cuts `s` to at most `max_bytes` bytes on a char boundary
+fn truncate(s: &str, max_bytes: usize) -> &str {
+    let mut end = max_bytes.min(s.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// Cuts `s` to at most `max_bytes` bytes, ending it with a hash of the full string
+fn shorten(s: &str, max_bytes: usize) -> String {
+    if s.len() <= max_bytes {
+        return s.to_string();
+    }
+    let hash = format!("_{:016x}", fnv1a(s));
+    if max_bytes <= hash.len() {
+        return truncate(&hash[1..], max_bytes).to_string();
+    }
+    format!("{}{}", truncate(s, max_bytes - hash.len()), hash)
+}
+
+/// Stable 64-bit FNV-1a hash
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud_sdk::google::api::Metric;
+    use std::collections::HashMap;
+
+    fn new_limiter(limits: GcmLimits) -> Limiter {
+        Limiter::new(limits, ExporterStatsHandle::default())
+    }
+
+    fn keys(descriptor: &MetricDescriptor) -> Vec<String> {
+        descriptor.labels.iter().map(|l| l.key.clone()).collect()
+    }
+
+    #[test]
+    fn test_shorten() {
+        assert_eq!(truncate("héllo", 2), "h");
+        assert_eq!(shorten("short", 10), "short");
+        let shortened = shorten(&"a".repeat(50), 30);
+        assert_eq!(shortened.len(), 30);
+        assert!(shortened.starts_with("aaaaaaaaaaaaa_"));
+        assert_ne!(shortened, shorten(&"a".repeat(51), 30));
+    }
+
+    #[test]
+    fn test_metric_type() {
+        let limiter = new_limiter(GcmLimits {
+            max_metric_type_length: 40,
+            ..Default::default()
+        });
+        let reported = |metric_type: &str| {
+            let mut alterations = limiter
+                .reported
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(t, _)| t == metric_type)
+                .map(|(_, alteration)| *alteration)
+                .collect::<Vec<Alteration>>();
+            alterations.sort_by_key(|alteration| format!("{:?}", alteration));
+            alterations
+        };
+        assert_eq!(
+            limiter.metric_type("workload.googleapis.com/my-metric".to_string()),
+            "workload.googleapis.com/my_metric"
+        );
+        assert_eq!(
+            reported("workload.googleapis.com/my-metric"),
+            vec![Alteration::MetricTypeSanitized]
+        );
+        let long_type = format!("workload.googleapis.com/{}", "m".repeat(40));
+        let long = limiter.metric_type(long_type.clone());
+        assert_eq!(long.len(), 40);
+        assert_eq!(reported(&long_type), vec![Alteration::MetricTypeShortened]);
+        let long_type = format!("workload.googleapis.com/my-{}", "m".repeat(40));
+        limiter.metric_type(long_type.clone());
+        assert_eq!(
+            reported(&long_type),
+            vec![Alteration::MetricTypeSanitized, Alteration::MetricTypeShortened]
+        );
+        limiter.metric_type("workload.googleapis.com/valid".to_string());
+        assert_eq!(reported("workload.googleapis.com/valid"), vec![]);
+    }
+
+    #[test]
+    fn test_add_labels() {
+        let limiter = new_limiter(GcmLimits {
+            max_labels: 2,
+            ..Default::default()
+        });
+        let mut descriptor = MetricDescriptor::default();
+        let added = limiter.add_labels(&mut descriptor, ["a", "b", "c"].map(String::from));
+        assert_eq!(added, 2);
+        assert_eq!(keys(&descriptor), vec!["a", "b"]);
+
+        let limiter = new_limiter(GcmLimits {
+            max_labels: 2,
+            excess_labels_policy: ExcessLabelsPolicy::Merge,
+            ..Default::default()
+        });
+        let mut descriptor = MetricDescriptor::default();
+        limiter.add_labels(&mut descriptor, ["a", "b", "c"].map(String::from));
+        assert_eq!(keys(&descriptor), vec!["a", EXTRA_LABELS_KEY]);
+    }
+
+    #[test]
+    fn test_limit_series() {
+        let stats = ExporterStatsHandle::default();
+        let limiter = Limiter::new(
+            GcmLimits {
+                max_labels: 3,
+                max_label_value_length: 8,
+                excess_labels_policy: ExcessLabelsPolicy::Merge,
+                ..Default::default()
+            },
+            stats.clone(),
+        );
+        let mut descriptor = MetricDescriptor::default();
+        limiter.add_labels(&mut descriptor, ["a", "b", "c", "d"].map(String::from));
+        assert_eq!(keys(&descriptor), vec!["a", "b", EXTRA_LABELS_KEY]);
+        let mut series = vec![TimeSeries {
+            metric: Some(Metric {
+                r#type: "workload.googleapis.com/m".to_string(),
+                labels: HashMap::from([
+                    ("a".to_string(), "a_very_long_value".to_string()),
+                    ("b".to_string(), "1".to_string()),
+                    ("c".to_string(), "2".to_string()),
+                    ("d".to_string(), "3".to_string()),
+                ]),
+            }),
+            ..Default::default()
+        }];
+        limiter.limit_series(&descriptor, &mut series);
+        assert_eq!(
+            series[0].metric.as_ref().unwrap().labels,
+            HashMap::from([
+                ("a".to_string(), "a_very_l".to_string()),
+                ("b".to_string(), "1".to_string()),
+                (EXTRA_LABELS_KEY.to_string(), "c=2,d=3".to_string()),
+            ])
+        );
+        assert_eq!(
+            stats.snapshot().label_alterations,
+            HashMap::from([("LabelsMerged".to_string(), 2), ("LabelValueTruncated".to_string(), 1),])
+        );
+    }
+}
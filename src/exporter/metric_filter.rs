@@ -0,0 +1,154 @@
+use opentelemetry::InstrumentationScope;
+use regex::Regex;
+
+/// Pattern matched against a whole metric name, scope name or scope version.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pattern {
+    /// `*` matches any characters, `?` a single one, e.g. `"http.client.*"`.
+    Glob(String),
+    /// Regular expression, see https://docs.rs/regex. It must match the whole value.
+    Regex(String),
+}
+
+impl Pattern {
+    pub(crate) fn compile(&self) -> Option<Regex> {
+        let regex = match self {
+            Pattern::Glob(glob) => {
+                let mut regex = String::from("^");
+                for c in glob.chars() {
+                    match c {
+                        '*' => regex.push_str(".*"),
+                        '?' => regex.push('.'),
+                        c => regex.push_str(&regex::escape(&c.to_string())),
+                    }
+                }
+                regex.push('$');
+                regex
+            }
+            Pattern::Regex(regex) => format!("^(?:{})$", regex),
+        };
+        match Regex::new(&regex) {
+            Ok(regex) => Some(regex),
+            Err(err) => {
+                tracing::warn!(
+                    pattern = ?self,
+                    error = %err,
+                    "GCPMetricsExporter: invalid metric filter pattern, it matches nothing"
+                );
+                None
+            }
+        }
+    }
+}
+
+/// Selects metrics by instrument name and instrumentation scope. A rule matches
+/// a metric when all its patterns match, unset patterns match anything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricFilterRule {
+    /// metric: pattern of the instrument name.
+    pub metric: Option<Pattern>,
+    /// scope_name: pattern of the instrumentation scope (meter) name.
+    pub scope_name: Option<Pattern>,
+    /// scope_version: pattern of the instrumentation scope version,
+    ///     scopes without version are matched as "".
+    pub scope_version: Option<Pattern>,
+}
+
+#[derive(Debug)]
+pub(crate) struct CompiledRule {
+    metric: Option<Option<Regex>>,
+    scope_name: Option<Option<Regex>>,
+    scope_version: Option<Option<Regex>>,
+}
+
+impl CompiledRule {
+    pub(crate) fn new(rule: &MetricFilterRule) -> Self {
+        Self {
+            metric: rule.metric.as_ref().map(Pattern::compile),
+            scope_name: rule.scope_name.as_ref().map(Pattern::compile),
+            scope_version: rule.scope_version.as_ref().map(Pattern::compile),
+        }
+    }
+
+    pub(crate) fn matches(&self, scope: &InstrumentationScope, metric: &str) -> bool {
+        let is_match = |pattern: &Option<Option<Regex>>, value: &str| match pattern {
+            None => true,
+            Some(regex) => regex.as_ref().is_some_and(|regex| regex.is_match(value)),
+        };
+        is_match(&self.metric, metric)
+            && is_match(&self.scope_name, scope.name())
+            && is_match(&self.scope_version, scope.version().unwrap_or_default())
+    }
+}
+
+/// Decides which metrics are exported: a metric is exported when it matches one
+/// of the include rules (or there are none) and none of the exclude rules.
+#[derive(Debug, Default)]
+pub(crate) struct MetricFilter {
+    include: Vec<CompiledRule>,
+    exclude: Vec<CompiledRule>,
+}
+
+impl MetricFilter {
+    pub(crate) fn new(include: &[MetricFilterRule], exclude: &[MetricFilterRule]) -> Self {
+        Self {
+            include: include.iter().map(CompiledRule::new).collect(),
+            exclude: exclude.iter().map(CompiledRule::new).collect(),
+        }
+    }
+
+    pub(crate) fn exports(&self, scope: &InstrumentationScope, metric: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|rule| rule.matches(scope, metric)))
+            && !self.exclude.iter().any(|rule| rule.matches(scope, metric))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pattern() {
+        let glob = Pattern::Glob("http.*.dura?ion".to_string()).compile().unwrap();
+        assert!(glob.is_match("http.client.duration"));
+        assert!(!glob.is_match("http.client.duration.sum"));
+        assert!(!glob.is_match("httpXclient.duration"));
+        let regex = Pattern::Regex("db|cache".to_string()).compile().unwrap();
+        assert!(regex.is_match("db"));
+        assert!(!regex.is_match("dbx"));
+        assert!(Pattern::Regex("(".to_string()).compile().is_none());
+    }
+
+    #[test]
+    fn test_exports() {
+        let hyper = InstrumentationScope::builder("hyper").with_version("1.2.0").build();
+        let app = InstrumentationScope::builder("app").build();
+        let filter = MetricFilter::new(
+            &[],
+            &[
+                MetricFilterRule {
+                    scope_name: Some(Pattern::Glob("hyper".to_string())),
+                    scope_version: Some(Pattern::Glob("1.*".to_string())),
+                    ..Default::default()
+                },
+                MetricFilterRule {
+                    metric: Some(Pattern::Regex(".*_debug".to_string())),
+                    ..Default::default()
+                },
+            ],
+        );
+        assert!(!filter.exports(&hyper, "requests"));
+        assert!(filter.exports(&app, "requests"));
+        assert!(!filter.exports(&app, "requests_debug"));
+
+        let filter = MetricFilter::new(
+            &[MetricFilterRule {
+                metric: Some(Pattern::Glob("http.*".to_string())),
+                ..Default::default()
+            }],
+            &[],
+        );
+        assert!(filter.exports(&hyper, "http.requests"));
+        assert!(!filter.exports(&app, "requests"));
+    }
+}
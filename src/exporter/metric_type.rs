@@ -0,0 +1,132 @@
+use super::metric_filter::{CompiledRule, MetricFilterRule};
+use opentelemetry::InstrumentationScope;
+use std::{
+    fmt::{Debug, Formatter},
+    sync::Arc,
+};
+
+/// Metric type prefix of the metrics matching `rule`, e.g. library metrics
+/// under "workload.googleapis.com/lib".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefixRule {
+    /// rule: metrics getting the prefix.
+    pub rule: MetricFilterRule,
+    /// prefix: metric type prefix, without the trailing "/".
+    pub prefix: String,
+}
+
+/// Computes the full metric type (e.g. "custom.googleapis.com/orders/created")
+/// from the instrumentation scope and the instrument name.
+#[derive(Clone)]
+pub struct MetricTypeFn(Arc<MetricTypeFnInner>);
+
+type MetricTypeFnInner = dyn Fn(&InstrumentationScope, &str) -> String + Send + Sync;
+
+impl MetricTypeFn {
+    pub fn new(f: impl Fn(&InstrumentationScope, &str) -> String + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+impl Debug for MetricTypeFn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MetricTypeFn")
+    }
+}
+
+impl PartialEq for MetricTypeFn {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for MetricTypeFn {}
+
+/// Picks the metric type of a metric: the user function if any, else the prefix
+/// of the first matching prefix rule, else the default prefix.
+#[derive(Debug)]
+pub(crate) struct MetricTypes {
+    prefix: String,
+    rules: Vec<(CompiledRule, String)>,
+    function: Option<MetricTypeFn>,
+}
+
+impl MetricTypes {
+    pub(crate) fn new(prefix: String, rules: &[PrefixRule], function: Option<MetricTypeFn>) -> Self {
+        Self {
+            prefix,
+            rules: rules
+                .iter()
+                .map(|rule| (CompiledRule::new(&rule.rule), rule.prefix.clone()))
+                .collect(),
+            function,
+        }
+    }
+
+    pub(crate) fn metric_type(&self, scope: &InstrumentationScope, metric: &str) -> String {
+        if let Some(function) = &self.function {
+            return (function.0)(scope, metric);
+        }
+        let prefix = self
+            .rules
+            .iter()
+            .find(|(rule, _)| rule.matches(scope, metric))
+            .map_or(self.prefix.as_str(), |(_, prefix)| prefix.as_str());
+        format!("{}/{}", prefix, metric)
+    }
+
+    /// Distinct prefixes of the metric types, the ones produced by the user function are unknown
+    pub(crate) fn prefixes(&self) -> Vec<&str> {
+        let mut prefixes = vec![self.prefix.as_str()];
+        for (_, prefix) in &self.rules {
+            if !prefixes.contains(&prefix.as_str()) {
+                prefixes.push(prefix);
+            }
+        }
+        prefixes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter::Pattern;
+
+    #[test]
+    fn test_metric_type() {
+        let app = InstrumentationScope::builder("app").build();
+        let hyper = InstrumentationScope::builder("hyper").build();
+        let metric_types = MetricTypes::new(
+            "custom.googleapis.com".to_string(),
+            &[PrefixRule {
+                rule: MetricFilterRule {
+                    scope_name: Some(Pattern::Glob("hyper*".to_string())),
+                    ..Default::default()
+                },
+                prefix: "workload.googleapis.com/lib".to_string(),
+            }],
+            None,
+        );
+        assert_eq!(metric_types.metric_type(&app, "orders"), "custom.googleapis.com/orders");
+        assert_eq!(
+            metric_types.metric_type(&hyper, "requests"),
+            "workload.googleapis.com/lib/requests"
+        );
+        assert_eq!(
+            metric_types.prefixes(),
+            vec!["custom.googleapis.com", "workload.googleapis.com/lib"]
+        );
+
+        let metric_types = MetricTypes::new(
+            "custom.googleapis.com".to_string(),
+            &[],
+            Some(MetricTypeFn::new(|scope, metric| {
+                format!("custom.googleapis.com/{}/{}", scope.name(), metric)
+            })),
+        );
+        assert_eq!(
+            metric_types.metric_type(&app, "orders"),
+            "custom.googleapis.com/app/orders"
+        );
+    }
+}
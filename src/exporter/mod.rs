@@ -1,18 +1,53 @@
+mod attributes;
 mod data_point_to_time_series;
+mod descriptor_conflict;
 mod histogram_data_point_to_time_series;
+mod in_flight;
+mod limits;
+mod metric_filter;
+mod metric_type;
+mod monitored_resource;
+mod partial_failure;
+mod resource_labels;
+mod retry;
+mod stats;
 mod to_f64;
+mod unit;
 mod utils;
 use crate::{
     gcloud_sdk,
     gcp_authorizer::{Authorizer, FakeAuthorizer, GoogleEnvironment},
 };
 
+pub use attributes::AttributeRules;
+use attributes::{reaggregate, AttributeFilter, Reaggregation};
+use descriptor_conflict::{coerce_values, missing_labels, values_coerced};
+pub use descriptor_conflict::{DescriptorConflict, DescriptorConflictResolution};
+use futures_util::{stream, StreamExt};
 use gcloud_sdk::google::{
-    api::{metric_descriptor, metric_descriptor::MetricKind, LabelDescriptor, MetricDescriptor},
-    monitoring::v3::{metric_service_client::MetricServiceClient, CreateTimeSeriesRequest, TimeSeries},
+    api::{metric_descriptor, metric_descriptor::MetricKind, LabelDescriptor, MetricDescriptor, MonitoredResource},
+    monitoring::v3::{
+        metric_service_client::MetricServiceClient, CreateTimeSeriesRequest, GetMetricDescriptorRequest,
+        ListMetricDescriptorsRequest, TimeSeries,
+    },
+};
+use histogram_data_point_to_time_series::estimate_sums_of_squared_deviation;
+use in_flight::{Drain, InFlightExports};
+use limits::Limiter;
+pub use limits::{ExcessLabelsPolicy, GcmLimits, LongLabelValuePolicy};
+use metric_filter::MetricFilter;
+pub use metric_filter::{MetricFilterRule, Pattern};
+use metric_type::MetricTypes;
+pub use metric_type::{MetricTypeFn, PrefixRule};
+use monitored_resource::MonitoredResources;
+pub use monitored_resource::{
+    DetectedMonitoredResource, FallbackMonitoredResource, MonitoredResourceMapper, StaticMonitoredResource,
+    TemplateMonitoredResource,
 };
-use itertools::Itertools;
-use opentelemetry_resourcedetector_gcp_rust::mapping::get_monitored_resource;
+use opentelemetry::InstrumentationScope;
+use partial_failure::CreateTimeSeriesFailure;
+pub use resource_labels::ResourceLabelConflict;
+use resource_labels::ResourceLabels;
 
 use opentelemetry_sdk::{
     error::OTelSdkError,
@@ -21,34 +56,85 @@ use opentelemetry_sdk::{
         exporter::PushMetricExporter as PushMetricsExporter,
         Temporality,
     },
+    Resource,
 };
 
 use rand::Rng;
+pub use retry::RetryPolicy;
+pub use stats::ExporterStats;
+pub use stats::ExporterStatsHandle;
 use std::{
     collections::{HashMap, HashSet},
     fmt::{Debug, Formatter},
-    sync::Arc,
-    time::{Duration, SystemTime},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 #[cfg(feature = "tokio")]
-use tokio::{sync::RwLock, time::sleep};
+use tokio::sync::RwLock;
 use tonic::{metadata::MetadataValue, transport::Channel};
-use utils::{get_data_points_attributes_keys, normalize_label_key};
+use tracing::Instrument;
+use unit::UnitTranslator;
+use utils::{
+    get_data_points_attributes, get_data_points_attributes_keys, kv_map_normalize_k_v, metric_kind_from_temporality,
+    normalize_label_key, split_time_series,
+};
 
 pub(crate) const UNIQUE_IDENTIFIER_KEY: &str = "opentelemetry_id";
+/// Labels holding the instrumentation scope name and version, as in the Go and Python exporters
+const INSTRUMENTATION_SOURCE_KEY: &str = "instrumentation_source";
+const INSTRUMENTATION_VERSION_KEY: &str = "instrumentation_version";
+/// Cloud Monitoring accepts at most 200 time series per CreateTimeSeries call
+pub(crate) const MAX_TIME_SERIES_PER_REQUEST: usize = 200;
+/// Highest `_vN` suffix tried by `DescriptorConflictResolution::VersionedMetricType`
+const MAX_METRIC_TYPE_VERSION: u32 = 10;
+/// How long `force_flush` waits for in-flight exports when the retry policy has no deadline
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
 pub type AuthorizerType = Arc<dyn Authorizer + Send + Sync>;
 
+/// Outcome of a successful `create_metric_descriptor` call
+enum CreatedDescriptor {
+    /// The descriptor was created or updated as sent
+    Created,
+    /// A descriptor of this type already exists, as stored in Cloud Monitoring
+    Existing(Box<MetricDescriptor>),
+    /// A descriptor of this type already exists, but couldn't be fetched
+    Unchecked,
+}
+
 /// Implementation of Metrics Exporter to Google Cloud Monitoring.
 pub struct GCPMetricsExporter {
-    prefix: String,
+    metric_types: MetricTypes,
     project_id: Option<String>,
     add_unique_identifier: bool,
+    add_scope_labels: bool,
+    add_scope_attributes: bool,
+    resource_labels: ResourceLabels,
     unique_identifier: String,
     authorizer: AuthorizerType,
-    is_test_env: bool,
+    endpoint: EndpointConfig,
     metric_descriptors: Arc<RwLock<HashMap<String, MetricDescriptor>>>,
-    custom_monitored_resource_data: Option<MonitoredResourceDataConfig>,
+    monitored_resources: MonitoredResources,
+    temporality: Temporality,
+    label_drift_policy: LabelDriftPolicy,
+    warm_descriptor_cache: bool,
+    descriptor_conflict_resolution: DescriptorConflictResolution,
+    units: UnitTranslator,
+    estimate_sum_of_squared_deviation: bool,
+    limiter: Limiter,
+    attribute_filter: AttributeFilter,
+    metric_filter: MetricFilter,
+    descriptor_cache_warmed: AtomicBool,
+    metric_service_client: Arc<RwLock<Option<MetricServiceClient<Channel>>>>,
+    retry_policy: RetryPolicy,
+    max_concurrent_requests: usize,
+    shutdown_deadline: Mutex<Option<Instant>>,
+    stats: ExporterStatsHandle,
+    in_flight: InFlightExports,
+    is_shutdown: AtomicBool,
 }
 
 /// Configuration for the GCP metrics exporter.
@@ -66,6 +152,144 @@ pub struct GCPMetricsExporterConfig {
     pub add_unique_identifier: bool,
     /// custom_monitored_resource_data: Custom monitored resource data to be
     pub custom_monitored_resource_data: Option<MonitoredResourceDataConfig>,
+    /// monitored_resource_mapper: computes the monitored resource from the resource
+    ///     attributes, e.g. a `TemplateMonitoredResource`. It wins over
+    ///     `custom_monitored_resource_data`, the resource is detected by
+    ///     `DetectedMonitoredResource` when neither is set.
+    pub monitored_resource_mapper: Option<Arc<dyn MonitoredResourceMapper>>,
+    /// fallback_monitored_resource: monitored resource built from `service.*` and
+    ///     host attributes when the mapper returns None, Cloud Monitoring rejects
+    ///     series without one. It is enabled with the "global" location by default.
+    ///     Only custom mappers return None, `DetectedMonitoredResource` falls back to
+    ///     `generic_task` or `generic_node` itself. It is reported once per type.
+    pub fallback_monitored_resource: Option<FallbackMonitoredResource>,
+    /// per_data_point_monitored_resource: map the monitored resource of every data
+    ///     point with `MonitoredResourceMapper::map_data_point`, e.g. for a gateway
+    ///     exporting metrics of many pods. The consumed attributes are removed
+    ///     from the metric labels, mappers consuming none map once per export.
+    pub per_data_point_monitored_resource: bool,
+    /// temporality: the aggregation temporality requested from the SDK. It is
+    ///     `Temporality::Cumulative` by default. `Temporality::Delta` exports
+    ///     counters and histograms as DELTA metrics so short-lived workers
+    ///     don't keep cumulative state. `Temporality::LowMemory` only uses
+    ///     delta for synchronous counters and histograms.
+    pub temporality: Temporality,
+    /// endpoint: the Cloud Monitoring API endpoint. It is
+    ///     "https://monitoring.googleapis.com" over TLS by default.
+    pub endpoint: EndpointConfig,
+    /// retry_policy: how failed Cloud Monitoring calls are retried.
+    pub retry_policy: RetryPolicy,
+    /// max_concurrent_requests: how many CreateTimeSeries calls of one export
+    ///     may be in flight at the same time. It is 1 (sequential) by default.
+    pub max_concurrent_requests: usize,
+    /// label_drift_policy: what to do when a metric gets attribute keys that
+    ///     are not labels of its already created descriptor. It is
+    ///     `LabelDriftPolicy::Update` by default.
+    pub label_drift_policy: LabelDriftPolicy,
+    /// warm_descriptor_cache: list the existing metric descriptors under
+    ///     `prefix` in `GCPMetricsExporter::init` and seed the descriptor cache
+    ///     with them instead of re-creating every descriptor after a restart.
+    ///     The first export warms the cache up if `init` wasn't called or failed.
+    ///     It is disabled by default.
+    pub warm_descriptor_cache: bool,
+    /// descriptor_conflict_resolution: what to do with a metric whose kind or
+    ///     value type differs from its existing descriptor. It is
+    ///     `DescriptorConflictResolution::SkipMetric` by default.
+    pub descriptor_conflict_resolution: DescriptorConflictResolution,
+    /// unit_overrides: OpenTelemetry unit -> Cloud Monitoring unit translations
+    ///     taking precedence over the built-in ones, e.g. `"Cel" -> "{degree_celsius}"`.
+    ///     Units Cloud Monitoring doesn't support are exported as an annotation.
+    pub unit_overrides: HashMap<String, String>,
+    /// estimate_sum_of_squared_deviation: fill the sum of squared deviation of
+    ///     distributions from bucket midpoints, OpenTelemetry doesn't record it.
+    ///     It is disabled by default (sent as 0).
+    pub estimate_sum_of_squared_deviation: bool,
+    /// limits: Cloud Monitoring limits on metric types and labels. Metric types
+    ///     and label keys over the limits are shortened with a hash suffix, label
+    ///     values and excess labels are handled per the configured policies.
+    pub limits: GcmLimits,
+    /// attribute_rules: attributes dropped or renamed before they become labels,
+    ///     for all metrics. Series that end up with the same labels are merged.
+    pub attribute_rules: AttributeRules,
+    /// metric_attribute_rules: attribute rules of single metrics, keyed by
+    ///     instrument name. They apply on top of `attribute_rules`.
+    pub metric_attribute_rules: HashMap<String, AttributeRules>,
+    /// include_metrics: when not empty, only the metrics matching one of these
+    ///     rules are exported.
+    pub include_metrics: Vec<MetricFilterRule>,
+    /// exclude_metrics: metrics matching one of these rules are not exported,
+    ///     and get no metric descriptor.
+    pub exclude_metrics: Vec<MetricFilterRule>,
+    /// prefix_rules: metric type prefixes of the metrics matching a rule, the
+    ///     first matching rule wins over `prefix`.
+    pub prefix_rules: Vec<PrefixRule>,
+    /// metric_type_fn: computes the full metric type, overrides `prefix` and
+    ///     `prefix_rules`. Its metric types are not warmed up by `warm_descriptor_cache`.
+    pub metric_type_fn: Option<MetricTypeFn>,
+    /// add_scope_labels: add the `instrumentation_source` and `instrumentation_version`
+    ///     labels holding the instrumentation scope name and version, so that metrics
+    ///     with the same name from two libraries can be told apart.
+    pub add_scope_labels: bool,
+    /// add_scope_attributes: add the instrumentation scope attributes as labels.
+    pub add_scope_attributes: bool,
+    /// resource_attributes_to_labels: resource attributes copied onto the labels of
+    ///     every series, e.g. `Pattern::Glob("service.version")`.
+    pub resource_attributes_to_labels: Vec<Pattern>,
+    /// resource_label_conflict: what to do when a copied resource attribute and a
+    ///     data point attribute have the same key. It is
+    ///     `ResourceLabelConflict::PreferDataPoint` by default.
+    pub resource_label_conflict: ResourceLabelConflict,
+}
+
+/// What to do when data points carry attribute keys missing from the cached metric descriptor.
+/// Cloud Monitoring rejects points with labels that are not in the descriptor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LabelDriftPolicy {
+    /// Re-create the descriptor with the merged label set.
+    #[default]
+    Update,
+    /// Keep the descriptor and drop the unknown labels from the points.
+    DropExtraLabels,
+    /// Keep the descriptor and drop the points with unknown labels.
+    Reject,
+}
+
+/// Cloud Monitoring API endpoint the exporter connects to.
+/// Use it for regional or Private Service Connect endpoints, or a plaintext
+/// local fake server/emulator.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndpointConfig {
+    /// url: the endpoint url, e.g. "https://monitoring.googleapis.com" or "http://localhost:50051"
+    pub url: String,
+    /// tls: connect over TLS. Set to false for plaintext emulator endpoints.
+    pub tls: bool,
+    /// tls_domain_name: overrides the domain name used to verify the server
+    ///     certificate. The url host is used by default.
+    pub tls_domain_name: Option<String>,
+}
+
+impl EndpointConfig {
+    pub fn tls<S: AsRef<str>>(url: S) -> Self {
+        Self {
+            url: url.as_ref().to_string(),
+            tls: true,
+            tls_domain_name: None,
+        }
+    }
+
+    pub fn plaintext<S: AsRef<str>>(url: S) -> Self {
+        Self {
+            url: url.as_ref().to_string(),
+            tls: false,
+            tls_domain_name: None,
+        }
+    }
+}
+
+impl Default for EndpointConfig {
+    fn default() -> Self {
+        Self::tls("https://monitoring.googleapis.com")
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -85,6 +309,29 @@ impl Default for GCPMetricsExporterConfig {
             project_id: None,
             add_unique_identifier: false,
             custom_monitored_resource_data: None,
+            monitored_resource_mapper: None,
+            fallback_monitored_resource: Some(FallbackMonitoredResource::default()),
+            per_data_point_monitored_resource: false,
+            temporality: Temporality::Cumulative,
+            endpoint: EndpointConfig::default(),
+            retry_policy: RetryPolicy::default(),
+            max_concurrent_requests: 1,
+            label_drift_policy: LabelDriftPolicy::Update,
+            warm_descriptor_cache: false,
+            descriptor_conflict_resolution: DescriptorConflictResolution::SkipMetric,
+            unit_overrides: HashMap::new(),
+            estimate_sum_of_squared_deviation: false,
+            limits: GcmLimits::default(),
+            attribute_rules: AttributeRules::default(),
+            metric_attribute_rules: HashMap::new(),
+            include_metrics: vec![],
+            exclude_metrics: vec![],
+            prefix_rules: vec![],
+            metric_type_fn: None,
+            add_scope_labels: false,
+            add_scope_attributes: false,
+            resource_attributes_to_labels: vec![],
+            resource_label_conflict: ResourceLabelConflict::PreferDataPoint,
         }
     }
 }
@@ -92,30 +339,118 @@ impl Default for GCPMetricsExporterConfig {
 impl GCPMetricsExporter {
     pub fn new(authorizer: AuthorizerType, config: GCPMetricsExporterConfig) -> Self {
         let my_rundom = format!("{:08x}", rand::rng().random_range(0..u32::MAX));
+        let stats = ExporterStatsHandle::default();
         Self {
-            prefix: config.prefix,
+            metric_types: MetricTypes::new(config.prefix, &config.prefix_rules, config.metric_type_fn),
             add_unique_identifier: config.add_unique_identifier,
+            add_scope_labels: config.add_scope_labels,
+            add_scope_attributes: config.add_scope_attributes,
+            resource_labels: ResourceLabels::new(&config.resource_attributes_to_labels, config.resource_label_conflict),
             project_id: config.project_id,
             unique_identifier: my_rundom,
             authorizer,
-            is_test_env: cfg!(test),
+            label_drift_policy: config.label_drift_policy,
+            warm_descriptor_cache: config.warm_descriptor_cache,
+            descriptor_conflict_resolution: config.descriptor_conflict_resolution,
+            units: UnitTranslator::new(config.unit_overrides),
+            estimate_sum_of_squared_deviation: config.estimate_sum_of_squared_deviation,
+            limiter: Limiter::new(config.limits, stats.clone()),
+            attribute_filter: AttributeFilter::new(config.attribute_rules, config.metric_attribute_rules),
+            metric_filter: MetricFilter::new(&config.include_metrics, &config.exclude_metrics),
+            descriptor_cache_warmed: AtomicBool::new(false),
+            endpoint: config.endpoint,
             metric_descriptors: Arc::new(RwLock::new(HashMap::new())),
-            custom_monitored_resource_data: config.custom_monitored_resource_data,
+            monitored_resources: MonitoredResources::new(
+                match (config.monitored_resource_mapper, config.custom_monitored_resource_data) {
+                    (Some(mapper), _) => mapper,
+                    (None, Some(data)) => Arc::new(StaticMonitoredResource(data)),
+                    (None, None) => Arc::new(DetectedMonitoredResource),
+                },
+                config.fallback_monitored_resource,
+                config.per_data_point_monitored_resource,
+            ),
+            temporality: config.temporality,
+            metric_service_client: Arc::new(RwLock::new(None)),
+            retry_policy: config.retry_policy,
+            max_concurrent_requests: config.max_concurrent_requests,
+            shutdown_deadline: Mutex::new(None),
+            stats,
+            in_flight: InFlightExports::default(),
+            is_shutdown: AtomicBool::new(false),
         }
     }
 
     pub async fn make_chanel(&self) -> Result<Channel, crate::error::Error> {
-        if self.is_test_env {
-            Channel::from_static("http://localhost:50051")
-                .connect_timeout(Duration::from_secs(30))
-                .tcp_keepalive(Some(Duration::from_secs(60)))
-                .keep_alive_timeout(Duration::from_secs(60))
-                .http2_keep_alive_interval(Duration::from_secs(60))
-                .connect()
-                .await
-                .map_err(|e| crate::error::ErrorKind::Other(e.to_string()).into())
-        } else {
-            GoogleEnvironment::init_google_services_channel("https://monitoring.googleapis.com").await
+        GoogleEnvironment::init_channel(&self.endpoint).await
+    }
+
+    /// Runs the start-up work enabled by the config, i.e. warms up the descriptor
+    /// cache with `warm_descriptor_cache`. `new_gcp_auth` calls it, call it after
+    /// `new` before handing the exporter to a reader. Failures are logged and the
+    /// first export tries again.
+    pub async fn init(&self) {
+        self.warm_up_descriptor_cache_once().await;
+    }
+
+    /// Handle to the exporter self-observability counters, e.g. to alert on
+    /// failed series or slow exports. Take it before handing the exporter to a reader.
+    pub fn stats_handle(&self) -> ExporterStatsHandle {
+        self.stats.clone()
+    }
+
+    /// Returns the shared client, connecting the channel on first use.
+    /// The underlying channel is cheap to clone and multiplexes all requests.
+    async fn get_metric_service_client(&self) -> Result<MetricServiceClient<Channel>, crate::error::Error> {
+        if let Some(client) = self.metric_service_client.read().await.as_ref() {
+            return Ok(client.clone());
+        }
+        let mut metric_service_client = self.metric_service_client.write().await;
+        if let Some(client) = metric_service_client.as_ref() {
+            return Ok(client.clone());
+        }
+        let client = MetricServiceClient::new(self.make_chanel().await?);
+        *metric_service_client = Some(client.clone());
+        Ok(client)
+    }
+
+    async fn get_metric_service_client_or_status(&self) -> Result<MetricServiceClient<Channel>, tonic::Status> {
+        self.get_metric_service_client().await.map_err(|err| {
+            tonic::Status::unavailable(format!(
+                "GCPMetricsExporter: Cant init google services grpc transport channel [Make issue with this case in github repo]: {:?}",
+                err
+            ))
+        })
+    }
+
+    /// Builds a request carrying the bearer token and the time left before the retry deadline
+    async fn authorized_request<T>(
+        &self,
+        message: T,
+        timeout: Option<Duration>,
+    ) -> Result<tonic::Request<T>, tonic::Status> {
+        let token =
+            self.authorizer.token().await.map_err(|err| {
+                tonic::Status::unauthenticated(format!("GCPMetricsExporter: cant authorize: {:?}", err))
+            })?;
+        let mut req = tonic::Request::new(message);
+        let header = MetadataValue::try_from(format!("Bearer {}", token.as_str()))
+            .map_err(|err| tonic::Status::unauthenticated(format!("GCPMetricsExporter: cant authorize: {:?}", err)))?;
+        req.metadata_mut().insert("authorization", header);
+        if let Some(timeout) = timeout {
+            req.set_timeout(timeout);
+        }
+        Ok(req)
+    }
+
+    /// Deadline set by `shutdown_with_timeout`, in-flight retries must not outlive it
+    fn shutdown_deadline(&self) -> Option<Instant> {
+        *self.shutdown_deadline.lock().unwrap()
+    }
+
+    /// Drops the shared client when the channel looks broken so the next call reconnects.
+    async fn reset_metric_service_client_if_broken(&self, status: &tonic::Status) {
+        if status.code() == tonic::Code::Unavailable {
+            *self.metric_service_client.write().await = None;
         }
     }
 }
@@ -124,13 +459,22 @@ impl GCPMetricsExporter {
 impl GCPMetricsExporter {
     pub async fn new_gcp_auth(config: GCPMetricsExporterConfig) -> Result<GCPMetricsExporter, gcp_auth::Error> {
         let auth = crate::gcp_auth_authorizer::GcpAuth::new().await?;
-        Ok(GCPMetricsExporter::new(Arc::new(auth), config))
+        let exporter = GCPMetricsExporter::new(Arc::new(auth), config);
+        exporter.init().await;
+        Ok(exporter)
     }
 }
 
 impl GCPMetricsExporter {
+    /// Exporter with a fake authorizer, pointed at a plaintext fake server on localhost:50051
     pub fn fake_new() -> GCPMetricsExporter {
-        GCPMetricsExporter::new(Arc::new(FakeAuthorizer::new()), GCPMetricsExporterConfig::default())
+        GCPMetricsExporter::new(
+            Arc::new(FakeAuthorizer::new()),
+            GCPMetricsExporterConfig {
+                endpoint: EndpointConfig::plaintext("http://localhost:50051"),
+                ..Default::default()
+            },
+        )
     }
 }
 
@@ -148,17 +492,18 @@ impl GCPMetricsExporter {
     ///
     /// :param record:
     /// :return:
-    async fn get_metric_descriptor(&self, metric: &OpentelemetrySdkMetric) -> Option<MetricDescriptor> {
-        let descriptor_type = format!("{}/{}", self.prefix, metric.name());
-        let cached_metric_descriptor = {
-            let metric_descriptors = self.metric_descriptors.read().await;
-            metric_descriptors.get(&descriptor_type).cloned()
-        };
-        if let Some(cached_metric_descriptor) = cached_metric_descriptor {
-            return Some(cached_metric_descriptor);
-        }
+    #[tracing::instrument(level = "debug", name = "get_metric_descriptor", skip_all, fields(metric_name = metric.name()))]
+    async fn get_metric_descriptor(
+        &self,
+        scope: &InstrumentationScope,
+        metric: &OpentelemetrySdkMetric,
+        resource_labels: &[(String, String)],
+    ) -> Option<MetricDescriptor> {
+        let descriptor_type = self
+            .limiter
+            .metric_type(self.metric_types.metric_type(scope, metric.name()));
 
-        let unit = metric.unit().to_string();
+        let unit = self.units.translate(metric.unit());
         let mut descriptor = MetricDescriptor {
             r#type: descriptor_type.clone(),
             display_name: metric.name().to_string(),
@@ -166,14 +511,6 @@ impl GCPMetricsExporter {
             unit: unit,
             ..Default::default()
         };
-        let seen_keys: HashSet<String> = get_data_points_attributes_keys(metric.data());
-
-        for key in &seen_keys {
-            descriptor.labels.push(LabelDescriptor {
-                key: normalize_label_key(key),
-                ..Default::default()
-            });
-        }
 
         // todo add unique identifier
         if self.add_unique_identifier {
@@ -183,19 +520,29 @@ impl GCPMetricsExporter {
             });
         }
 
+        let scope_keys = self.scope_labels(scope).into_iter().map(|(key, _)| key);
+        let keys = self.point_label_keys(metric);
+        let resource_keys = self
+            .resource_labels
+            .for_metric(resource_labels, &keys)
+            .into_iter()
+            .map(|(key, _)| key);
+        self.limiter
+            .add_labels(&mut descriptor, scope_keys.chain(resource_keys).chain(keys));
+
         match metric.data() {
             AggregatedMetrics::F64(v) => match v {
-                MetricData::Histogram(_) => {
-                    descriptor.metric_kind = MetricKind::Cumulative.into();
+                MetricData::Histogram(m) => {
+                    descriptor.metric_kind = metric_kind_from_temporality(m.temporality()).into();
                     descriptor.value_type = metric_descriptor::ValueType::Distribution.into();
                 }
-                MetricData::ExponentialHistogram(_) => {
-                    descriptor.metric_kind = MetricKind::Cumulative.into();
+                MetricData::ExponentialHistogram(m) => {
+                    descriptor.metric_kind = metric_kind_from_temporality(m.temporality()).into();
                     descriptor.value_type = metric_descriptor::ValueType::Distribution.into();
                 }
                 MetricData::Sum(m) => {
                     descriptor.metric_kind = if m.is_monotonic() {
-                        MetricKind::Cumulative.into()
+                        metric_kind_from_temporality(m.temporality()).into()
                     } else {
                         MetricKind::Gauge.into()
                     };
@@ -207,17 +554,17 @@ impl GCPMetricsExporter {
                 }
             },
             AggregatedMetrics::I64(v) => match v {
-                MetricData::Histogram(_) => {
-                    descriptor.metric_kind = MetricKind::Cumulative.into();
+                MetricData::Histogram(m) => {
+                    descriptor.metric_kind = metric_kind_from_temporality(m.temporality()).into();
                     descriptor.value_type = metric_descriptor::ValueType::Distribution.into();
                 }
-                MetricData::ExponentialHistogram(_) => {
-                    descriptor.metric_kind = MetricKind::Cumulative.into();
+                MetricData::ExponentialHistogram(m) => {
+                    descriptor.metric_kind = metric_kind_from_temporality(m.temporality()).into();
                     descriptor.value_type = metric_descriptor::ValueType::Distribution.into();
                 }
                 MetricData::Sum(m) => {
                     descriptor.metric_kind = if m.is_monotonic() {
-                        MetricKind::Cumulative.into()
+                        metric_kind_from_temporality(m.temporality()).into()
                     } else {
                         MetricKind::Gauge.into()
                     };
@@ -229,17 +576,17 @@ impl GCPMetricsExporter {
                 }
             },
             AggregatedMetrics::U64(v) => match v {
-                MetricData::Histogram(_) => {
-                    descriptor.metric_kind = MetricKind::Cumulative.into();
+                MetricData::Histogram(m) => {
+                    descriptor.metric_kind = metric_kind_from_temporality(m.temporality()).into();
                     descriptor.value_type = metric_descriptor::ValueType::Distribution.into();
                 }
-                MetricData::ExponentialHistogram(_) => {
-                    descriptor.metric_kind = MetricKind::Cumulative.into();
+                MetricData::ExponentialHistogram(m) => {
+                    descriptor.metric_kind = metric_kind_from_temporality(m.temporality()).into();
                     descriptor.value_type = metric_descriptor::ValueType::Distribution.into();
                 }
                 MetricData::Sum(m) => {
                     descriptor.metric_kind = if m.is_monotonic() {
-                        MetricKind::Cumulative.into()
+                        metric_kind_from_temporality(m.temporality()).into()
                     } else {
                         MetricKind::Gauge.into()
                     };
@@ -252,85 +599,440 @@ impl GCPMetricsExporter {
             },
         }
 
-        let project_id = self
-            .project_id
-            .clone()
-            .unwrap_or(self.authorizer.project_id().to_string());
-        let channel = match self.make_chanel().await {
-            Ok(channel) => channel,
-            Err(err) => {
-                utils::log_warning(format!(
-                    "GCPMetricsExporter: Cant init google services grpc transport channel [Make issue with this case in github repo]: {:?}",
-                    err
-                ));
-                return None;
+        let cached_metric_descriptor = {
+            let metric_descriptors = self.metric_descriptors.read().await;
+            metric_descriptors.get(&descriptor_type).cloned()
+        };
+        let resolved = match cached_metric_descriptor {
+            Some(cached_metric_descriptor) => {
+                match DescriptorConflict::detect(&cached_metric_descriptor, &descriptor) {
+                    None => return Some(self.update_label_drift(cached_metric_descriptor, &descriptor).await),
+                    // the cache was warmed up with a conflicting descriptor, or holds the coerced one
+                    Some(conflict) => {
+                        self.resolve_with_label_drift(conflict, cached_metric_descriptor, &descriptor)
+                            .await?
+                    }
+                }
             }
+            None => self.ensure_metric_descriptor(&descriptor).await?,
         };
-        let mut msc = MetricServiceClient::new(channel);
-        let mut iteration = 0;
-        loop {
-            iteration += 1;
-            if iteration > 101 {
-                utils::log_warning(format!("GCPMetricsExporter: Cant create_metric_descriptor"));
-                return None;
+
+        {
+            let mut metric_descriptors = self.metric_descriptors.write().await;
+            metric_descriptors.insert(descriptor_type, resolved.clone());
+            self.stats.record_descriptor_cache_size(metric_descriptors.len());
+        }
+        Some(resolved)
+    }
+
+    /// Creates the descriptor, checking that an already existing one matches it.
+    /// Returns the descriptor the points must be written against.
+    async fn ensure_metric_descriptor(&self, descriptor: &MetricDescriptor) -> Option<MetricDescriptor> {
+        let existing = match self.create_metric_descriptor(descriptor).await {
+            Ok(CreatedDescriptor::Existing(existing)) => *existing,
+            Ok(CreatedDescriptor::Created | CreatedDescriptor::Unchecked) => return Some(descriptor.clone()),
+            Err(_) => return None,
+        };
+        match DescriptorConflict::detect(&existing, descriptor) {
+            Some(conflict) => {
+                // later exports resolve the conflict from the cache without calling Cloud Monitoring
+                self.metric_descriptors
+                    .write()
+                    .await
+                    .insert(descriptor.r#type.clone(), existing.clone());
+                self.resolve_with_label_drift(conflict, existing, descriptor).await
             }
-            let mut req = tonic::Request::new(gcloud_sdk::google::monitoring::v3::CreateMetricDescriptorRequest {
-                name: format!("projects/{}", project_id),
-                metric_descriptor: Some(descriptor.clone()),
-            });
-            match self.authorizer.token().await {
-                Ok(token) => {
-                    req.metadata_mut().insert(
-                        "authorization",
-                        MetadataValue::try_from(format!("Bearer {}", token.as_str())).unwrap(),
+            None => {
+                let missing_labels = missing_labels(&existing, descriptor);
+                if !missing_labels.is_empty() {
+                    tracing::warn!(
+                        metric_type = %descriptor.r#type,
+                        missing_labels = ?missing_labels,
+                        "GCPMetricsExporter: existing metric descriptor lacks labels"
                     );
                 }
-                Err(err) => {
-                    utils::log_warning(format!("GCPMetricsExporter: cant authorize: {:?}", err));
-                    return None;
-                }
+                Some(descriptor.clone())
             }
+        }
+    }
 
-            match msc.create_metric_descriptor(req).await {
-                Ok(_resp) => break,
-                Err(err) => {
-                    // logger.error(
-                    //     "Failed to create metric descriptor %s",
-                    //     descriptor,
-                    //     exc_info=ex,
-                    // )
-                    utils::log_warning(format!(
-                        "GCPMetricsExporter: Retry send create_metric_descriptor: {:?}",
-                        err
-                    ));
-                    match err.code() {
-                        tonic::Code::Unavailable
-                        | tonic::Code::DataLoss
-                        | tonic::Code::DeadlineExceeded
-                        | tonic::Code::Aborted
-                        | tonic::Code::Internal
-                        | tonic::Code::FailedPrecondition => {
-                            sleep(Duration::from_millis(200)).await;
-                            continue;
-                        }
-                        tonic::Code::AlreadyExists => {
-                            break;
+    /// Resolves the conflict between `descriptor` and `existing`. Points coerced to the
+    /// existing value type are written against `existing`, which gets the new labels
+    /// of `descriptor` as a matching descriptor would.
+    async fn resolve_with_label_drift(
+        &self,
+        conflict: DescriptorConflict,
+        existing: MetricDescriptor,
+        descriptor: &MetricDescriptor,
+    ) -> Option<MetricDescriptor> {
+        let resolved = self.resolve_descriptor_conflict(conflict, descriptor).await?;
+        if resolved.r#type != descriptor.r#type {
+            return Some(resolved);
+        }
+        Some(self.update_label_drift(existing, &resolved).await)
+    }
+
+    /// Applies the configured `DescriptorConflictResolution`. The conflict is
+    /// reported once per metric type and kept in the exporter stats.
+    async fn resolve_descriptor_conflict(
+        &self,
+        conflict: DescriptorConflict,
+        descriptor: &MetricDescriptor,
+    ) -> Option<MetricDescriptor> {
+        let first_report = self.stats.record_descriptor_conflict(&conflict);
+        match self.descriptor_conflict_resolution {
+            DescriptorConflictResolution::CoerceValueType if conflict.is_coercible() => {
+                if first_report {
+                    tracing::warn!(
+                        metric_type = %conflict.metric_type,
+                        conflict = %conflict,
+                        "GCPMetricsExporter: metric descriptor conflict, points coerced to the existing value type"
+                    );
+                }
+                return Some(MetricDescriptor {
+                    value_type: conflict.existing_value_type.into(),
+                    ..descriptor.clone()
+                });
+            }
+            DescriptorConflictResolution::VersionedMetricType => {
+                for version in 2..=MAX_METRIC_TYPE_VERSION {
+                    let versioned = MetricDescriptor {
+                        r#type: format!("{}_v{}", descriptor.r#type, version),
+                        ..descriptor.clone()
+                    };
+                    // a version is only used once its descriptor is known to accept the points
+                    match self.create_metric_descriptor(&versioned).await {
+                        Ok(CreatedDescriptor::Existing(existing))
+                            if DescriptorConflict::detect(&existing, &versioned).is_some()
+                                || !missing_labels(&existing, &versioned).is_empty() =>
+                        {
+                            continue
                         }
-                        _ => {
-                            return None;
+                        Ok(CreatedDescriptor::Unchecked) => continue,
+                        Ok(_) => {
+                            if first_report {
+                                tracing::warn!(
+                                    metric_type = %conflict.metric_type,
+                                    versioned_metric_type = %versioned.r#type,
+                                    conflict = %conflict,
+                                    "GCPMetricsExporter: metric descriptor conflict, metric written under a versioned type"
+                                );
+                            }
+                            return Some(versioned);
                         }
+                        Err(_) => return None,
                     }
                 }
             }
+            _ => {}
+        }
+        if first_report {
+            tracing::error!(
+                metric_type = %conflict.metric_type,
+                conflict = %conflict,
+                "GCPMetricsExporter: metric descriptor conflicts with the existing one, metric skipped"
+            );
         }
+        None
+    }
 
-        {
-            let mut metric_descriptors = self.metric_descriptors.write().await;
-            metric_descriptors.insert(descriptor_type, descriptor.clone());
+    /// Seeds the descriptor cache with the descriptors under the prefixes that already
+    /// exist in Cloud Monitoring, so that a restarted process doesn't re-create them
+    /// and schema conflicts are detected before points are written.
+    /// Returns the number of descriptors added to the cache.
+    pub async fn warm_up_descriptor_cache(&self) -> Result<usize, tonic::Status> {
+        let project_id = self
+            .project_id
+            .clone()
+            .unwrap_or(self.authorizer.project_id().to_string());
+        let mut existing = Vec::<MetricDescriptor>::new();
+        for prefix in self.metric_types.prefixes() {
+            let mut page_token = String::new();
+            loop {
+                let list_request = ListMetricDescriptorsRequest {
+                    name: format!("projects/{}", project_id),
+                    filter: format!("metric.type = starts_with(\"{}/\")", prefix),
+                    page_size: 0,
+                    page_token: page_token.clone(),
+                };
+                let request = &list_request;
+                let resp = self
+                    .retry_policy
+                    .run(
+                        "list_metric_descriptors",
+                        || self.shutdown_deadline(),
+                        |attempt, time_left| async move {
+                            if attempt > 1 {
+                                self.stats.record_retry();
+                            }
+                            let req = self.authorized_request(request.clone(), time_left).await?;
+                            let mut msc = self.get_metric_service_client_or_status().await?;
+                            let started = Instant::now();
+                            let resp = msc.list_metric_descriptors(req).await;
+                            self.stats.record_rpc(started.elapsed());
+                            if let Err(err) = &resp {
+                                self.reset_metric_service_client_if_broken(err).await;
+                            }
+                            resp
+                        },
+                    )
+                    .await?
+                    .into_inner();
+                existing.extend(resp.metric_descriptors);
+                if resp.next_page_token.is_empty() {
+                    break;
+                }
+                page_token = resp.next_page_token;
+            }
+        }
+
+        let mut metric_descriptors = self.metric_descriptors.write().await;
+        let cached = metric_descriptors.len();
+        for descriptor in existing {
+            metric_descriptors
+                .entry(descriptor.r#type.clone())
+                .or_insert(descriptor);
+        }
+        self.stats.record_descriptor_cache_size(metric_descriptors.len());
+        let added = metric_descriptors.len() - cached;
+        tracing::debug!(added, "GCPMetricsExporter: metric descriptor cache warmed up");
+        Ok(added)
+    }
+
+    /// Warms up the descriptor cache with `warm_descriptor_cache` unless it already was
+    async fn warm_up_descriptor_cache_once(&self) {
+        if self.warm_descriptor_cache && !self.descriptor_cache_warmed.swap(true, Ordering::SeqCst) {
+            if let Err(err) = self.warm_up_descriptor_cache().await {
+                tracing::warn!(
+                    code = ?err.code(),
+                    message = err.message(),
+                    "GCPMetricsExporter: Cant warm up the metric descriptor cache"
+                );
+                self.descriptor_cache_warmed.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Handles labels of the local descriptor missing from the cached one according
+    /// to the label drift policy. With `LabelDriftPolicy::Update` the descriptor is
+    /// re-created with the merged label set and the cache refreshed.
+    async fn update_label_drift(&self, cached: MetricDescriptor, local: &MetricDescriptor) -> MetricDescriptor {
+        if self.label_drift_policy != LabelDriftPolicy::Update {
+            return cached;
+        }
+        let mut new_keys = local
+            .labels
+            .iter()
+            .map(|label| label.key.clone())
+            .filter(|key| !cached.labels.iter().any(|label| &label.key == key))
+            .collect::<Vec<String>>();
+        new_keys.sort();
+        new_keys.dedup();
+        let mut descriptor = cached.clone();
+        if self.limiter.add_labels(&mut descriptor, new_keys) == 0 {
+            return cached;
+        }
+        tracing::info!(
+            metric_type = %cached.r#type,
+            new_labels = ?descriptor.labels[cached.labels.len()..].iter().map(|l| &l.key).collect::<Vec<_>>(),
+            "GCPMetricsExporter: new attribute keys, updating metric descriptor"
+        );
+        if self.create_metric_descriptor(&descriptor).await.is_err() {
+            return cached;
+        }
+        let mut metric_descriptors = self.metric_descriptors.write().await;
+        metric_descriptors.insert(local.r#type.clone(), descriptor.clone());
+        descriptor
+    }
+
+    /// Monitored resources of the data points of a metric, in order. Empty unless they
+    /// are mapped per data point, the points then use the one of the export.
+    fn data_point_resources(&self, resource: &Resource, data: &AggregatedMetrics) -> Vec<Option<MonitoredResource>> {
+        if !self.monitored_resources.per_data_point() {
+            return vec![];
+        }
+        get_data_points_attributes(data)
+            .iter()
+            .map(|attributes| {
+                self.monitored_resources
+                    .map_data_point(resource, attributes)
+                    .map(|v| MonitoredResource {
+                        r#type: v.r#type,
+                        labels: v.labels,
+                    })
+            })
+            .collect()
+    }
+
+    /// Sorted label keys of the data point attributes of a metric, after the attribute rules
+    fn point_label_keys(&self, metric: &OpentelemetrySdkMetric) -> Vec<String> {
+        let seen_keys: HashSet<String> = get_data_points_attributes_keys(metric.data());
+        let mut keys = seen_keys
+            .iter()
+            .map(|key| normalize_label_key(key))
+            .filter(|key| !self.monitored_resources.is_consumed(key))
+            .filter_map(|key| self.attribute_filter.label_key(metric.name(), &key))
+            .collect::<Vec<String>>();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+
+    /// Labels of the instrumentation scope added to every series of its metrics,
+    /// they win over data point attributes with the same key
+    fn scope_labels(&self, scope: &InstrumentationScope) -> Vec<(String, String)> {
+        let mut labels = Vec::new();
+        if self.add_scope_labels {
+            labels.push((INSTRUMENTATION_SOURCE_KEY.to_string(), scope.name().to_string()));
+            labels.push((
+                INSTRUMENTATION_VERSION_KEY.to_string(),
+                scope.version().unwrap_or_default().to_string(),
+            ));
+        }
+        if self.add_scope_attributes {
+            labels.extend(scope.attributes().map(kv_map_normalize_k_v));
+        }
+        labels
+    }
+
+    /// Makes the series of a metric fit the labels of its descriptor when the
+    /// policy doesn't update descriptors. Returns whether a label was dropped.
+    fn apply_label_drift_policy(&self, descriptor: &MetricDescriptor, series: &mut Vec<TimeSeries>) -> bool {
+        let known_label = |key: &String| descriptor.labels.iter().any(|label| &label.key == key);
+        let mut dropped = false;
+        match self.label_drift_policy {
+            LabelDriftPolicy::Update => {}
+            LabelDriftPolicy::DropExtraLabels => {
+                for ts in series.iter_mut() {
+                    if let Some(metric) = ts.metric.as_mut() {
+                        let before = metric.labels.len();
+                        metric.labels.retain(|key, _| known_label(key));
+                        dropped |= metric.labels.len() < before;
+                    }
+                }
+            }
+            LabelDriftPolicy::Reject => {
+                let before = series.len();
+                series.retain(|ts| ts.metric.as_ref().map_or(true, |m| m.labels.keys().all(known_label)));
+                if series.len() < before {
+                    tracing::warn!(
+                        metric_type = %descriptor.r#type,
+                        rejected = before - series.len(),
+                        "GCPMetricsExporter: series with labels missing from the metric descriptor rejected"
+                    );
+                    self.stats
+                        .record_series_failed(tonic::Code::InvalidArgument, (before - series.len()) as u64);
+                }
+            }
         }
-        Some(descriptor)
+        dropped
     }
 
+    /// Sends the descriptor to Cloud Monitoring. Returns the stored descriptor
+    /// when one of this type already exists and could be fetched.
+    async fn create_metric_descriptor(
+        &self,
+        descriptor: &MetricDescriptor,
+    ) -> Result<CreatedDescriptor, tonic::Status> {
+        let project_id = self
+            .project_id
+            .clone()
+            .unwrap_or(self.authorizer.project_id().to_string());
+        let request = gcloud_sdk::google::monitoring::v3::CreateMetricDescriptorRequest {
+            name: format!("projects/{}", project_id),
+            metric_descriptor: Some(descriptor.clone()),
+        };
+        let request = &request;
+        let resp = self
+            .retry_policy
+            .run(
+                "create_metric_descriptor",
+                || self.shutdown_deadline(),
+                |attempt, time_left| async move {
+                    if attempt > 1 {
+                        self.stats.record_retry();
+                    }
+                    let req = self.authorized_request(request.clone(), time_left).await?;
+                    let mut msc = self.get_metric_service_client_or_status().await?;
+                    let started = Instant::now();
+                    let resp = msc.create_metric_descriptor(req).await;
+                    self.stats.record_rpc(started.elapsed());
+                    if let Err(err) = &resp {
+                        self.reset_metric_service_client_if_broken(err).await;
+                    }
+                    resp
+                },
+            )
+            .await;
+        match resp {
+            Ok(_resp) => {
+                tracing::debug!(metric_type = %descriptor.r#type, "GCPMetricsExporter: metric descriptor created");
+            }
+            Err(err) if err.code() == tonic::Code::AlreadyExists => {
+                tracing::debug!(metric_type = %descriptor.r#type, "GCPMetricsExporter: metric descriptor already exists");
+                return Ok(self
+                    .fetch_metric_descriptor(&project_id, &descriptor.r#type)
+                    .await
+                    .map_or(CreatedDescriptor::Unchecked, |existing| {
+                        CreatedDescriptor::Existing(Box::new(existing))
+                    }));
+            }
+            Err(err) => {
+                tracing::error!(
+                    metric_type = %descriptor.r#type,
+                    project_id = %project_id,
+                    code = ?err.code(),
+                    message = err.message(),
+                    "GCPMetricsExporter: Cant create_metric_descriptor"
+                );
+                return Err(err);
+            }
+        }
+        Ok(CreatedDescriptor::Created)
+    }
+
+    /// Fetches a stored descriptor, `None` when it can't be read
+    async fn fetch_metric_descriptor(&self, project_id: &str, metric_type: &str) -> Option<MetricDescriptor> {
+        let request = GetMetricDescriptorRequest {
+            name: format!("projects/{}/metricDescriptors/{}", project_id, metric_type),
+        };
+        let request = &request;
+        let resp = self
+            .retry_policy
+            .run(
+                "get_metric_descriptor",
+                || self.shutdown_deadline(),
+                |attempt, time_left| async move {
+                    if attempt > 1 {
+                        self.stats.record_retry();
+                    }
+                    let req = self.authorized_request(request.clone(), time_left).await?;
+                    let mut msc = self.get_metric_service_client_or_status().await?;
+                    let started = Instant::now();
+                    let resp = msc.get_metric_descriptor(req).await;
+                    self.stats.record_rpc(started.elapsed());
+                    if let Err(err) = &resp {
+                        self.reset_metric_service_client_if_broken(err).await;
+                    }
+                    resp
+                },
+            )
+            .await;
+        match resp {
+            Ok(resp) => Some(resp.into_inner()),
+            Err(err) => {
+                tracing::warn!(
+                    metric_type = %metric_type,
+                    code = ?err.code(),
+                    message = err.message(),
+                    "GCPMetricsExporter: Cant get_metric_descriptor, existing descriptor not checked"
+                );
+                None
+            }
+        }
+    }
+
+    #[tracing::instrument(level = "debug", name = "exec_export", skip_all, fields(series = tracing::field::Empty))]
     async fn exec_export(&self, metrics: &ResourceMetrics) -> Result<(), OTelSdkError> {
         // // println!("export: {:#?}", metrics);
         // let proto_message: ExportMetricsServiceRequest = (&*metrics).into();
@@ -339,62 +1041,90 @@ impl GCPMetricsExporter {
         // use std::io::Write;
         // let mut file = std::fs::File::create("metrics.txt").unwrap();
         // file.write_all(format!("{:#?}", metrics).as_bytes()).unwrap();
-        let monitored_resource_data = match self.custom_monitored_resource_data.clone() {
-            Some(custom_monitored_resource_data) => Some(gcloud_sdk::google::api::MonitoredResource {
-                r#type: custom_monitored_resource_data.r#type,
-                labels: custom_monitored_resource_data.labels,
-            }),
-            None => get_monitored_resource(metrics.resource()).map(|v| gcloud_sdk::google::api::MonitoredResource {
-                r#type: v.r#type,
-                labels: v.labels,
-            }),
-        };
+        // fallback when `init` wasn't called or failed
+        self.warm_up_descriptor_cache_once().await;
+        let monitored_resource_data =
+            self.monitored_resources
+                .map(metrics.resource())
+                .map(|v| gcloud_sdk::google::api::MonitoredResource {
+                    r#type: v.r#type,
+                    labels: v.labels,
+                });
 
+        let project_id = self
+            .project_id
+            .clone()
+            .unwrap_or(self.authorizer.project_id().to_string());
+        let project_id = project_id.as_str();
+
+        let resource_labels = self.resource_labels.select(metrics.resource());
         let mut all_series = Vec::<TimeSeries>::new();
         for scope_metric in metrics.scope_metrics() {
             for metric in scope_metric.metrics() {
-                let descriptor: MetricDescriptor = if let Some(descriptor) = self.get_metric_descriptor(metric).await {
+                if !self.metric_filter.exports(scope_metric.scope(), metric.name()) {
+                    continue;
+                }
+                let descriptor: MetricDescriptor = if let Some(descriptor) = self
+                    .get_metric_descriptor(scope_metric.scope(), metric, &resource_labels)
+                    .await
+                {
                     descriptor
                 } else {
                     continue;
                 };
+                let point_resources = self.data_point_resources(metrics.resource(), metric.data());
+                let mut metric_series = Vec::<TimeSeries>::new();
                 match metric.data() {
                     AggregatedMetrics::F64(v) => match v {
                         MetricData::Histogram(m) => {
-                            for data_point in m.data_points() {
-                                all_series.push(histogram_data_point_to_time_series::convert(
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(histogram_data_point_to_time_series::convert(
+                                    data_point,
+                                    &m.start_time(),
+                                    &m.time(),
+                                    &descriptor,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
+                                    self.add_unique_identifier,
+                                    self.unique_identifier.as_str(),
+                                    project_id,
+                                ));
+                            }
+                        }
+                        MetricData::ExponentialHistogram(m) => {
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(histogram_data_point_to_time_series::convert_exponential(
                                     data_point,
                                     &m.start_time(),
                                     &m.time(),
                                     &descriptor,
-                                    &monitored_resource_data,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
                                     self.add_unique_identifier,
                                     self.unique_identifier.as_str(),
+                                    project_id,
                                 ));
                             }
                         }
-                        MetricData::ExponentialHistogram(_) => {}
                         MetricData::Sum(m) => {
-                            for data_point in m.data_points() {
-                                all_series.push(data_point_to_time_series::sum_convert_f64(
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(data_point_to_time_series::sum_convert_f64(
                                     data_point,
                                     &m.start_time(),
                                     &m.time(),
                                     &descriptor,
-                                    &monitored_resource_data,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
                                     self.add_unique_identifier,
                                     self.unique_identifier.clone(),
                                 ));
                             }
                         }
                         MetricData::Gauge(m) => {
-                            for data_point in m.data_points() {
-                                all_series.push(data_point_to_time_series::gauge_convert_f64(
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(data_point_to_time_series::gauge_convert_f64(
                                     data_point,
                                     &m.start_time(),
                                     &m.time(),
                                     &descriptor,
-                                    &monitored_resource_data,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
                                     self.add_unique_identifier,
                                     self.unique_identifier.clone(),
                                 ));
@@ -403,40 +1133,54 @@ impl GCPMetricsExporter {
                     },
                     AggregatedMetrics::I64(v) => match v {
                         MetricData::Histogram(m) => {
-                            for data_point in m.data_points() {
-                                all_series.push(histogram_data_point_to_time_series::convert(
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(histogram_data_point_to_time_series::convert(
                                     data_point,
                                     &m.start_time(),
                                     &m.time(),
                                     &descriptor,
-                                    &monitored_resource_data,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
                                     self.add_unique_identifier,
                                     self.unique_identifier.as_str(),
+                                    project_id,
+                                ));
+                            }
+                        }
+                        MetricData::ExponentialHistogram(m) => {
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(histogram_data_point_to_time_series::convert_exponential(
+                                    data_point,
+                                    &m.start_time(),
+                                    &m.time(),
+                                    &descriptor,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
+                                    self.add_unique_identifier,
+                                    self.unique_identifier.as_str(),
+                                    project_id,
                                 ));
                             }
                         }
-                        MetricData::ExponentialHistogram(_) => {}
                         MetricData::Sum(m) => {
-                            for data_point in m.data_points() {
-                                all_series.push(data_point_to_time_series::sum_convert_i64(
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(data_point_to_time_series::sum_convert_i64(
                                     data_point,
                                     &m.start_time(),
                                     &m.time(),
                                     &descriptor,
-                                    &monitored_resource_data,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
                                     self.add_unique_identifier,
                                     self.unique_identifier.clone(),
                                 ));
                             }
                         }
                         MetricData::Gauge(m) => {
-                            for data_point in m.data_points() {
-                                all_series.push(data_point_to_time_series::gauge_convert_i64(
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(data_point_to_time_series::gauge_convert_i64(
                                     data_point,
                                     &m.start_time(),
                                     &m.time(),
                                     &descriptor,
-                                    &monitored_resource_data,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
                                     self.add_unique_identifier,
                                     self.unique_identifier.clone(),
                                 ));
@@ -445,40 +1189,54 @@ impl GCPMetricsExporter {
                     },
                     AggregatedMetrics::U64(v) => match v {
                         MetricData::Histogram(m) => {
-                            for data_point in m.data_points() {
-                                all_series.push(histogram_data_point_to_time_series::convert(
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(histogram_data_point_to_time_series::convert(
                                     data_point,
                                     &m.start_time(),
                                     &m.time(),
                                     &descriptor,
-                                    &monitored_resource_data,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
                                     self.add_unique_identifier,
                                     self.unique_identifier.as_str(),
+                                    project_id,
+                                ));
+                            }
+                        }
+                        MetricData::ExponentialHistogram(m) => {
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(histogram_data_point_to_time_series::convert_exponential(
+                                    data_point,
+                                    &m.start_time(),
+                                    &m.time(),
+                                    &descriptor,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
+                                    self.add_unique_identifier,
+                                    self.unique_identifier.as_str(),
+                                    project_id,
                                 ));
                             }
                         }
-                        MetricData::ExponentialHistogram(_) => {}
                         MetricData::Sum(m) => {
-                            for data_point in m.data_points() {
-                                all_series.push(data_point_to_time_series::sum_convert_i64(
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(data_point_to_time_series::sum_convert_i64(
                                     data_point,
                                     &m.start_time(),
                                     &m.time(),
                                     &descriptor,
-                                    &monitored_resource_data,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
                                     self.add_unique_identifier,
                                     self.unique_identifier.clone(),
                                 ));
                             }
                         }
                         MetricData::Gauge(m) => {
-                            for data_point in m.data_points() {
-                                all_series.push(data_point_to_time_series::gauge_convert_i64(
+                            for (i, data_point) in m.data_points().enumerate() {
+                                metric_series.push(data_point_to_time_series::gauge_convert_i64(
                                     data_point,
                                     &m.start_time(),
                                     &m.time(),
                                     &descriptor,
-                                    &monitored_resource_data,
+                                    point_resources.get(i).unwrap_or(&monitored_resource_data),
                                     self.add_unique_identifier,
                                     self.unique_identifier.clone(),
                                 ));
@@ -486,84 +1244,139 @@ impl GCPMetricsExporter {
                         }
                     },
                 }
+                // series can only collide once one of their labels was removed or rewritten
+                let mut rewritten = self.monitored_resources.remove_consumed(&mut metric_series);
+                rewritten |= self.attribute_filter.apply(metric.name(), &mut metric_series);
+                let scope_labels = self.scope_labels(scope_metric.scope());
+                for ts in metric_series.iter_mut() {
+                    if let Some(m) = ts.metric.as_mut() {
+                        for (key, value) in scope_labels.iter().cloned() {
+                            rewritten |= m.labels.insert(key, value).is_some();
+                        }
+                    }
+                }
+                if !resource_labels.is_empty() {
+                    let metric_resource_labels = self
+                        .resource_labels
+                        .for_metric(&resource_labels, &self.point_label_keys(metric));
+                    rewritten |= self.resource_labels.apply(&metric_resource_labels, &mut metric_series);
+                }
+                rewritten |= self.limiter.limit_series(&descriptor, &mut metric_series);
+                rewritten |= self.apply_label_drift_policy(&descriptor, &mut metric_series);
+                if rewritten {
+                    metric_series = reaggregate(metric_series, Reaggregation::of(metric.data()));
+                }
+                if values_coerced(&descriptor, metric.data()) {
+                    coerce_values(&mut metric_series);
+                }
+                if self.estimate_sum_of_squared_deviation {
+                    estimate_sums_of_squared_deviation(&mut metric_series);
+                }
+                all_series.extend(metric_series);
             }
         }
         // println!("all_series len: {}", all_series.len());
-        let chunked_all_series: Vec<Vec<TimeSeries>> = all_series
-            .into_iter()
-            .chunks(200)
-            .into_iter()
-            .map(|chunk| chunk.collect())
-            .collect();
+        tracing::Span::current().record("series", all_series.len());
         // todo add more usefull error handling and retry
-        let project_id = self
-            .project_id
-            .clone()
-            .unwrap_or(self.authorizer.project_id().to_string());
-        for chunk in chunked_all_series {
-            let mut iteration = 0;
-            loop {
-                iteration += 1;
-                if iteration > 101 {
-                    return Err(OTelSdkError::InternalFailure(
-                        "GCPMetricsExporter: Cant send time series".into(),
-                    ));
-                }
-                // todo optimize clones
-                let create_time_series_request = CreateTimeSeriesRequest {
-                    name: format!("projects/{}", project_id),
-                    time_series: chunk.clone(),
-                };
-                // println!("chunk: {:?}", create_time_series_request);
-                let mut req = tonic::Request::new(create_time_series_request.clone());
-                match self.authorizer.token().await {
-                    Ok(token) => {
-                        req.metadata_mut().insert(
-                            "authorization",
-                            MetadataValue::try_from(format!("Bearer {}", token.as_str())).unwrap(),
-                        );
-                    }
-                    Err(err) => {
-                        return Err(OTelSdkError::InternalFailure(format!(
-                            "GCPMetricsExporter: cant authorize: {:?}",
-                            err
-                        )));
-                    }
-                }
-                let channel = match self.make_chanel().await {
-                    Ok(channel) => channel,
-                    Err(err) => {
-                        return Err(OTelSdkError::InternalFailure(format!(
-                            "GCPMetricsExporter: Cant init google services grpc transport channel [Make issue with this case in github repo]: {:?}",
-                            err
-                        )));
+        // rounds are sent one after another so points of the same series are written in order,
+        // a failed chunk doesn't stop the other ones
+        let mut errors = Vec::<OTelSdkError>::new();
+        for round in split_time_series(all_series, MAX_TIME_SERIES_PER_REQUEST) {
+            let results = stream::iter(round)
+                .map(|chunk| self.send_time_series(project_id, chunk))
+                .buffer_unordered(self.max_concurrent_requests.max(1))
+                .collect::<Vec<_>>()
+                .await;
+            errors.extend(results.into_iter().filter_map(Result::err));
+        }
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            failed => Err(OTelSdkError::InternalFailure(format!(
+                "GCPMetricsExporter: {} CreateTimeSeries calls failed: {}",
+                failed,
+                errors
+                    .iter()
+                    .map(|err| err.to_string())
+                    .collect::<Vec<String>>()
+                    .join("; ")
+            ))),
+        }
+    }
+
+    #[tracing::instrument(level = "debug", name = "send_time_series", skip_all, fields(project_id = project_id, chunk_size = chunk.len()))]
+    async fn send_time_series(&self, project_id: &str, chunk: Vec<TimeSeries>) -> Result<(), OTelSdkError> {
+        // todo optimize clones
+        let create_time_series_request = CreateTimeSeriesRequest {
+            name: format!("projects/{}", project_id),
+            time_series: chunk,
+        };
+        let request = &create_time_series_request;
+        let resp = self
+            .retry_policy
+            .run(
+                "create_time_series",
+                || self.shutdown_deadline(),
+                |attempt, time_left| async move {
+                    if attempt > 1 {
+                        self.stats.record_retry();
                     }
-                };
-                let mut msc = MetricServiceClient::new(channel);
-                if let Err(err) = msc.create_time_series(req).await {
-                    utils::log_warning(format!("GCPMetricsExporter: Cant send time series: {:?}", err));
-                    match err.code() {
-                        tonic::Code::Unavailable
-                        | tonic::Code::DataLoss
-                        | tonic::Code::DeadlineExceeded
-                        | tonic::Code::Aborted
-                        | tonic::Code::Internal
-                        | tonic::Code::FailedPrecondition => {
-                            sleep(Duration::from_millis(200)).await;
-                            continue;
-                        }
-                        _ => {
-                            utils::log_warning(format!(
-                                "GCPMetricsExporter: Cant send time series: Request: {:?}",
-                                create_time_series_request
-                            ));
-                            break;
-                        }
+                    let req = self.authorized_request(request.clone(), time_left).await?;
+                    let mut msc = self.get_metric_service_client_or_status().await?;
+                    let started = Instant::now();
+                    let resp = msc.create_time_series(req).await;
+                    self.stats.record_rpc(started.elapsed());
+                    if let Err(err) = &resp {
+                        self.reset_metric_service_client_if_broken(err).await;
                     }
-                } else {
-                    break;
-                }
+                    resp
+                },
+            )
+            .await;
+        if let Err(err) = resp {
+            let failure = CreateTimeSeriesFailure::from_status(&err, &create_time_series_request);
+            if !failure.has_summary {
+                tracing::error!(
+                    project_id = project_id,
+                    code = ?err.code(),
+                    message = err.message(),
+                    chunk_size = create_time_series_request.time_series.len(),
+                    "GCPMetricsExporter: Cant send time series"
+                );
+                self.stats
+                    .record_series_failed(err.code(), create_time_series_request.time_series.len() as u64);
+                return Err(OTelSdkError::InternalFailure(format!(
+                    "GCPMetricsExporter: Cant send time series: {:?}",
+                    err
+                )));
             }
+            let series_count = create_time_series_request.time_series.len();
+            self.stats
+                .record_series_sent(failure.sent_series_count(series_count) as u64);
+            tracing::warn!(
+                project_id = project_id,
+                code = ?err.code(),
+                success_point_count = failure.success_point_count,
+                total_point_count = failure.total_point_count,
+                failed_point_count = failure.failed_point_count(),
+                "GCPMetricsExporter: time series partially written"
+            );
+            for error in &failure.errors {
+                tracing::warn!(
+                    project_id = project_id,
+                    code = ?error.code,
+                    point_count = error.point_count,
+                    failed_series_count = error.time_series_indexes.len(),
+                    failed_series = ?error.failed_series(&create_time_series_request),
+                    "GCPMetricsExporter: points failed: {}",
+                    error.message
+                );
+                self.stats
+                    .record_series_failed(error.code, error.failed_series_count(series_count) as u64);
+            }
+        } else {
+            self.stats
+                .record_series_sent(create_time_series_request.time_series.len() as u64);
         }
         Ok(())
     }
@@ -572,27 +1385,78 @@ impl GCPMetricsExporter {
 impl PushMetricsExporter for GCPMetricsExporter {
     fn export(&self, metrics: &ResourceMetrics) -> impl std::future::Future<Output = Result<(), OTelSdkError>> + Send {
         async {
+            if self.is_shutdown.load(Ordering::SeqCst) {
+                tracing::warn!("GCPMetricsExporter: export called after shutdown, metrics dropped");
+                return Err(OTelSdkError::AlreadyShutdown);
+            }
+            let _in_flight = self.in_flight.start();
             let sys_time = SystemTime::now();
             let resp = self.exec_export(metrics).await;
             let new_sys_time = SystemTime::now();
-            let _difference = new_sys_time
+            let difference = new_sys_time
                 .duration_since(sys_time)
-                .expect("Clock may have gone backwards")
-                .as_millis();
-            // info!("export time: {}", difference);
+                .expect("Clock may have gone backwards");
+            self.stats.record_export(difference, resp.is_ok());
+            tracing::debug!(
+                duration_ms = difference.as_millis() as u64,
+                ok = resp.is_ok(),
+                "GCPMetricsExporter: export finished"
+            );
             resp
         }
+        .instrument(tracing::info_span!("gcp_metrics_export"))
     }
 
+    /// Nothing is buffered by the exporter, flushing waits for in-flight
+    /// exports for at most the retry policy deadline. It can't wait on a
+    /// current-thread runtime, nor on a multi-thread one without the
+    /// "tokio-multi-thread" feature: the exports complete in the background.
     fn force_flush(&self) -> Result<(), OTelSdkError> {
-        Ok(()) // In this implementation, flush does nothing
+        if self.is_shutdown.load(Ordering::SeqCst) {
+            return Err(OTelSdkError::AlreadyShutdown);
+        }
+        let timeout = self.retry_policy.deadline.unwrap_or(DEFAULT_DRAIN_TIMEOUT);
+        match self.in_flight.wait_drained(timeout) {
+            Drain::Drained | Drain::InBackground(_) => Ok(()),
+            Drain::TimedOut(_) => Err(OTelSdkError::Timeout(timeout)),
+        }
     }
 
     fn temporality(&self) -> Temporality {
-        Temporality::default()
+        self.temporality
     }
 
-    fn shutdown_with_timeout(&self, _timeout: Duration) -> opentelemetry_sdk::error::OTelSdkResult {
-        Ok(())
+    /// Rejects later exports and waits up to `timeout` for in-flight exports.
+    /// Retries of in-flight exports are cut short to fit in `timeout`.
+    /// Returns `OTelSdkError::Timeout` when some exports did not complete, their data may be lost.
+    /// On a runtime it can't block (see `force_flush`) it doesn't wait, and returns
+    /// `OTelSdkError::InternalFailure` when exports are still in flight: they keep running
+    /// in the background for at most `timeout`, but their data may be lost.
+    fn shutdown_with_timeout(&self, timeout: Duration) -> opentelemetry_sdk::error::OTelSdkResult {
+        if self.is_shutdown.swap(true, Ordering::SeqCst) {
+            return Err(OTelSdkError::AlreadyShutdown);
+        }
+        *self.shutdown_deadline.lock().unwrap() = Some(Instant::now() + timeout);
+        match self.in_flight.wait_drained(timeout) {
+            Drain::Drained => Ok(()),
+            Drain::InBackground(in_flight) => {
+                tracing::error!(
+                    in_flight,
+                    "GCPMetricsExporter: cant wait for in-flight exports on this runtime, data may be lost"
+                );
+                Err(OTelSdkError::InternalFailure(format!(
+                    "GCPMetricsExporter: {} in-flight exports not drained",
+                    in_flight
+                )))
+            }
+            Drain::TimedOut(in_flight) => {
+                tracing::error!(
+                    in_flight,
+                    timeout_ms = timeout.as_millis() as u64,
+                    "GCPMetricsExporter: shutdown timed out before in-flight exports completed, data may be lost"
+                );
+                Err(OTelSdkError::Timeout(timeout))
+            }
+        }
     }
 }
@@ -0,0 +1,455 @@
+use super::{utils::normalize_label_key, MonitoredResourceDataConfig};
+use crate::gcloud_sdk::google::monitoring::v3::TimeSeries;
+use opentelemetry::{Key, KeyValue};
+use opentelemetry_resourcedetector_gcp_rust::mapping::get_monitored_resource;
+use opentelemetry_sdk::Resource;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+/// Maps the OpenTelemetry resource to the Cloud Monitoring monitored resource of
+/// the exported series, see https://cloud.google.com/monitoring/api/resources
+pub trait MonitoredResourceMapper: Debug + Send + Sync {
+    /// Monitored resource of all the series of an export, None sends them without one.
+    fn map(&self, resource: &Resource) -> Option<MonitoredResourceDataConfig>;
+
+    /// Monitored resource of a single data point, its attributes can refine the
+    /// resource ones. It is `map(resource)` by default. Only called with
+    /// `per_data_point_monitored_resource`, for mappers consuming attributes.
+    fn map_data_point(&self, resource: &Resource, attributes: &[KeyValue]) -> Option<MonitoredResourceDataConfig> {
+        let _ = attributes;
+        self.map(resource)
+    }
+
+    /// Data point attributes moved into the monitored resource by `map_data_point`,
+    /// they are removed from the metric labels. None by default, the resource is
+    /// then mapped once per export.
+    fn consumed_attributes(&self) -> Vec<String> {
+        vec![]
+    }
+}
+
+/// Mappers are compared by identity, so that `GCPMetricsExporterConfig` stays `Eq`
+impl PartialEq for dyn MonitoredResourceMapper {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self as *const Self as *const (), other as *const Self as *const ())
+    }
+}
+
+impl Eq for dyn MonitoredResourceMapper {}
+
+/// Monitored resource detected from the resource attributes (GCE, GKE, Cloud Run, ...)
+/// by `opentelemetry_resourcedetector_gcp_rust`. It is the default mapper. It never
+/// returns None: resources of unknown platforms are detected as `generic_task` or
+/// `generic_node`, so the fallback doesn't apply to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DetectedMonitoredResource;
+
+impl MonitoredResourceMapper for DetectedMonitoredResource {
+    fn map(&self, resource: &Resource) -> Option<MonitoredResourceDataConfig> {
+        get_monitored_resource(resource).map(|v| MonitoredResourceDataConfig {
+            r#type: v.r#type,
+            labels: v.labels,
+        })
+    }
+}
+
+/// The same monitored resource for every series, see `custom_monitored_resource_data`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticMonitoredResource(pub MonitoredResourceDataConfig);
+
+impl MonitoredResourceMapper for StaticMonitoredResource {
+    fn map(&self, _resource: &Resource) -> Option<MonitoredResourceDataConfig> {
+        Some(self.0.clone())
+    }
+}
+
+/// Monitored resource whose label values are templates over the resource (and data
+/// point) attributes, e.g. `"${service.namespace}/${service.name}"`. `${key:-default}`
+/// falls back to `default` when the attribute is missing, otherwise it expands to "".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateMonitoredResource {
+    /// r#type: monitored resource type, e.g. "generic_task".
+    pub r#type: String,
+    /// labels: label -> template of its value.
+    pub labels: HashMap<String, String>,
+}
+
+impl TemplateMonitoredResource {
+    fn render(&self, lookup: impl Fn(&str) -> Option<String>) -> MonitoredResourceDataConfig {
+        MonitoredResourceDataConfig {
+            r#type: self.r#type.clone(),
+            labels: self
+                .labels
+                .iter()
+                .map(|(label, template)| (label.clone(), render_template(template, &lookup)))
+                .collect(),
+        }
+    }
+}
+
+impl MonitoredResourceMapper for TemplateMonitoredResource {
+    fn map(&self, resource: &Resource) -> Option<MonitoredResourceDataConfig> {
+        Some(self.render(|key| resource.get(&Key::from(key.to_string())).map(|v| v.to_string())))
+    }
+
+    fn map_data_point(&self, resource: &Resource, attributes: &[KeyValue]) -> Option<MonitoredResourceDataConfig> {
+        Some(self.render(|key| {
+            attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.to_string())
+                .or_else(|| resource.get(&Key::from(key.to_string())).map(|v| v.to_string()))
+        }))
+    }
+
+    /// Every key referenced by a template, resource keys such as `${cloud.region}`
+    /// included: a data point attribute with one of these keys always overrides the
+    /// resource attribute and is removed from the metric labels, even when the
+    /// resource attribute is copied with `resource_labels`.
+    fn consumed_attributes(&self) -> Vec<String> {
+        let mut keys = self
+            .labels
+            .values()
+            .flat_map(|template| template_keys(template))
+            .map(str::to_string)
+            .collect::<Vec<String>>();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+}
+
+/// Monitored resource used when the mapper returns None, which only custom mappers do:
+/// `generic_task` for resources with a `service.name`, else `generic_node`, so that
+/// the series are not rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FallbackMonitoredResource {
+    /// location: location label when the resource has no `cloud.availability_zone`
+    ///     or `cloud.region`. It is "global" by default.
+    pub location: String,
+}
+
+impl Default for FallbackMonitoredResource {
+    fn default() -> Self {
+        Self {
+            location: "global".to_string(),
+        }
+    }
+}
+
+impl MonitoredResourceMapper for FallbackMonitoredResource {
+    fn map(&self, resource: &Resource) -> Option<MonitoredResourceDataConfig> {
+        let attribute = |key: &'static str| resource.get(&Key::from_static_str(key)).map(|v| v.to_string());
+        let location = attribute("cloud.availability_zone")
+            .or_else(|| attribute("cloud.region"))
+            .unwrap_or_else(|| self.location.clone());
+        let namespace = attribute("service.namespace").unwrap_or_default();
+        let host = || attribute("host.name").or_else(hostname).unwrap_or_default();
+        let mut labels = HashMap::from([("location".to_string(), location), ("namespace".to_string(), namespace)]);
+        let r#type = match attribute("service.name") {
+            Some(job) => {
+                labels.insert("job".to_string(), job);
+                labels.insert(
+                    "task_id".to_string(),
+                    attribute("service.instance.id").unwrap_or_else(host),
+                );
+                "generic_task"
+            }
+            None => {
+                labels.insert("node_id".to_string(), attribute("host.id").unwrap_or_else(host));
+                "generic_node"
+            }
+        };
+        Some(MonitoredResourceDataConfig {
+            r#type: r#type.to_string(),
+            labels,
+        })
+    }
+}
+
+/// Host name of the machine, from `HOSTNAME` or /etc/hostname, read once
+fn hostname() -> Option<String> {
+    static HOSTNAME: OnceLock<Option<String>> = OnceLock::new();
+    HOSTNAME
+        .get_or_init(|| {
+            std::env::var("HOSTNAME")
+                .ok()
+                .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+        })
+        .clone()
+}
+
+/// The configured mapper followed by the fallback
+#[derive(Debug)]
+pub(crate) struct MonitoredResources {
+    mapper: Arc<dyn MonitoredResourceMapper>,
+    fallback: Option<FallbackMonitoredResource>,
+    /// Types of the fallback resources already reported
+    fallback_reported: Mutex<HashSet<String>>,
+    per_data_point: bool,
+    /// Normalized label keys of the consumed attributes
+    consumed: Vec<String>,
+}
+
+impl MonitoredResources {
+    pub(crate) fn new(
+        mapper: Arc<dyn MonitoredResourceMapper>,
+        fallback: Option<FallbackMonitoredResource>,
+        per_data_point: bool,
+    ) -> Self {
+        let consumed = if per_data_point {
+            mapper
+                .consumed_attributes()
+                .iter()
+                .map(|key| normalize_label_key(key))
+                .collect::<Vec<String>>()
+        } else {
+            vec![]
+        };
+        Self {
+            mapper,
+            fallback,
+            fallback_reported: Mutex::new(HashSet::new()),
+            // the resource of a point can only differ from the export one through consumed attributes
+            per_data_point: !consumed.is_empty(),
+            consumed,
+        }
+    }
+
+    pub(crate) fn map(&self, resource: &Resource) -> Option<MonitoredResourceDataConfig> {
+        self.mapper.map(resource).or_else(|| self.fallback(resource))
+    }
+
+    pub(crate) fn per_data_point(&self) -> bool {
+        self.per_data_point
+    }
+
+    pub(crate) fn map_data_point(
+        &self,
+        resource: &Resource,
+        attributes: &[KeyValue],
+    ) -> Option<MonitoredResourceDataConfig> {
+        self.mapper
+            .map_data_point(resource, attributes)
+            .or_else(|| self.fallback(resource))
+    }
+
+    /// Whether the label `key` was moved into the monitored resource
+    pub(crate) fn is_consumed(&self, key: &str) -> bool {
+        self.consumed.iter().any(|k| k == key)
+    }
+
+    /// Removes the labels moved into the monitored resource. Returns whether
+    /// one was removed.
+    pub(crate) fn remove_consumed(&self, series: &mut [TimeSeries]) -> bool {
+        if self.consumed.is_empty() {
+            return false;
+        }
+        let mut removed = false;
+        for m in series.iter_mut().filter_map(|ts| ts.metric.as_mut()) {
+            let before = m.labels.len();
+            m.labels.retain(|key, _| !self.is_consumed(key));
+            removed |= m.labels.len() < before;
+        }
+        removed
+    }
+
+    fn fallback(&self, resource: &Resource) -> Option<MonitoredResourceDataConfig> {
+        let monitored_resource = self.fallback.as_ref()?.map(resource)?;
+        if self
+            .fallback_reported
+            .lock()
+            .unwrap()
+            .insert(monitored_resource.r#type.clone())
+        {
+            tracing::warn!(
+                r#type = %monitored_resource.r#type,
+                labels = ?monitored_resource.labels,
+                "GCPMetricsExporter: no monitored resource detected, using the fallback one"
+            );
+        }
+        Some(monitored_resource)
+    }
+}
+
+/// Attribute keys referenced by `template`
+fn template_keys(template: &str) -> Vec<&str> {
+    let mut keys = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        let placeholder = &rest[start + 2..start + 2 + len];
+        keys.push(placeholder.split(":-").next().unwrap_or_default());
+        rest = &rest[start + 2 + len + 1..];
+    }
+    keys
+}
+
+/// Replaces the `${key}` and `${key:-default}` placeholders of `template`
+fn render_template(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        let Some(len) = rest[start + 2..].find('}') else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start + 2..start + 2 + len];
+        let (key, default) = match placeholder.split_once(":-") {
+            Some((key, default)) => (key, default),
+            None => (placeholder, ""),
+        };
+        rendered.push_str(&lookup(key).unwrap_or_else(|| default.to_string()));
+        rest = &rest[start + 2 + len + 1..];
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let lookup = |key: &str| (key == "service.name").then(|| "demo".to_string());
+        assert_eq!(render_template("${service.name}", lookup), "demo");
+        assert_eq!(render_template("ns/${service.name}-1", lookup), "ns/demo-1");
+        assert_eq!(render_template("${service.namespace}", lookup), "");
+        assert_eq!(render_template("${service.namespace:-default}", lookup), "default");
+        assert_eq!(render_template("${unclosed", lookup), "${unclosed");
+        assert_eq!(template_keys("${a}/${b:-x}/${c"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_fallback_monitored_resource() {
+        let fallback = FallbackMonitoredResource {
+            location: "us-east1".to_string(),
+        };
+        let resource = Resource::builder_empty()
+            .with_attributes([
+                KeyValue::new("service.name", "demo"),
+                KeyValue::new("service.namespace", "shop"),
+                KeyValue::new("service.instance.id", "demo-1"),
+            ])
+            .build();
+        assert_eq!(
+            fallback.map(&resource),
+            Some(MonitoredResourceDataConfig {
+                r#type: "generic_task".to_string(),
+                labels: HashMap::from([
+                    ("location".to_string(), "us-east1".to_string()),
+                    ("namespace".to_string(), "shop".to_string()),
+                    ("job".to_string(), "demo".to_string()),
+                    ("task_id".to_string(), "demo-1".to_string()),
+                ]),
+            })
+        );
+        let resource = Resource::builder_empty()
+            .with_attributes([
+                KeyValue::new("host.id", "node-1"),
+                KeyValue::new("cloud.region", "europe-west1"),
+            ])
+            .build();
+        assert_eq!(
+            fallback.map(&resource),
+            Some(MonitoredResourceDataConfig {
+                r#type: "generic_node".to_string(),
+                labels: HashMap::from([
+                    ("location".to_string(), "europe-west1".to_string()),
+                    ("namespace".to_string(), "".to_string()),
+                    ("node_id".to_string(), "node-1".to_string()),
+                ]),
+            })
+        );
+    }
+
+    #[test]
+    fn test_fallback_reported_per_type() {
+        #[derive(Debug)]
+        struct Undetected;
+        impl MonitoredResourceMapper for Undetected {
+            fn map(&self, _resource: &Resource) -> Option<MonitoredResourceDataConfig> {
+                None
+            }
+        }
+        let monitored_resources =
+            MonitoredResources::new(Arc::new(Undetected), Some(FallbackMonitoredResource::default()), false);
+        let task = Resource::builder_empty()
+            .with_attributes([KeyValue::new("service.name", "demo")])
+            .build();
+        let node = Resource::builder_empty().build();
+        assert_eq!(monitored_resources.map(&task).unwrap().r#type, "generic_task");
+        assert_eq!(monitored_resources.map(&task).unwrap().r#type, "generic_task");
+        assert_eq!(monitored_resources.map(&node).unwrap().r#type, "generic_node");
+        let mut reported = monitored_resources
+            .fallback_reported
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<String>>();
+        reported.sort();
+        assert_eq!(reported, vec!["generic_node", "generic_task"]);
+    }
+
+    #[test]
+    fn test_template_monitored_resource() {
+        let mapper = TemplateMonitoredResource {
+            r#type: "generic_task".to_string(),
+            labels: HashMap::from([
+                ("job".to_string(), "${service.name}".to_string()),
+                ("task_id".to_string(), "${task.id:-0}".to_string()),
+            ]),
+        };
+        let resource = Resource::builder_empty()
+            .with_attributes([KeyValue::new("service.name", "demo")])
+            .build();
+        assert_eq!(
+            mapper.map(&resource),
+            Some(MonitoredResourceDataConfig {
+                r#type: "generic_task".to_string(),
+                labels: HashMap::from([
+                    ("job".to_string(), "demo".to_string()),
+                    ("task_id".to_string(), "0".to_string()),
+                ]),
+            })
+        );
+        assert_eq!(
+            mapper
+                .map_data_point(&resource, &[KeyValue::new("task.id", "7")])
+                .unwrap()
+                .labels["task_id"],
+            "7"
+        );
+        // a point without the attribute falls back to the resource, then to the default
+        let labels = mapper
+            .map_data_point(&resource, &[KeyValue::new("route", "/")])
+            .unwrap()
+            .labels;
+        assert_eq!(labels["job"], "demo");
+        assert_eq!(labels["task_id"], "0");
+        // resource keys are consumed too
+        assert_eq!(mapper.consumed_attributes(), vec!["service.name", "task.id"]);
+    }
+
+    #[test]
+    fn test_per_data_point() {
+        let template = TemplateMonitoredResource {
+            r#type: "generic_task".to_string(),
+            labels: HashMap::from([("task_id".to_string(), "${task.id}".to_string())]),
+        };
+        let monitored_resources = MonitoredResources::new(Arc::new(template), None, true);
+        assert!(monitored_resources.per_data_point());
+        assert!(monitored_resources.is_consumed("task_id"));
+        let monitored_resources = MonitoredResources::new(Arc::new(DetectedMonitoredResource), None, true);
+        assert!(!monitored_resources.per_data_point());
+    }
+}
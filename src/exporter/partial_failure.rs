@@ -0,0 +1,287 @@
+use crate::gcloud_sdk::google::{
+    monitoring::v3::{CreateTimeSeriesError, CreateTimeSeriesRequest, CreateTimeSeriesSummary},
+    rpc,
+};
+use prost::Message;
+use std::collections::BTreeSet;
+
+const CREATE_TIME_SERIES_SUMMARY_TYPE: &str = "google.monitoring.v3.CreateTimeSeriesSummary";
+const CREATE_TIME_SERIES_ERROR_TYPE: &str = "google.monitoring.v3.CreateTimeSeriesError";
+/// Failed series named in the log of one error, the other ones are only counted
+const MAX_REPORTED_SERIES: usize = 10;
+
+/// One category of rejected points of a CreateTimeSeries call.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct TimeSeriesError {
+    pub code: tonic::Code,
+    pub message: String,
+    pub point_count: i32,
+    /// indexes in the request of the series named by `message`
+    pub time_series_indexes: Vec<usize>,
+}
+
+impl TimeSeriesError {
+    /// Number of series of a request of `series_count` series rejected by this error:
+    /// the ones named by its message, else its points as every series has one point
+    pub(crate) fn failed_series_count(&self, series_count: usize) -> usize {
+        let named = self.time_series_indexes.iter().filter(|i| **i < series_count).count();
+        if named > 0 {
+            named
+        } else {
+            (self.point_count.max(0) as usize).min(series_count)
+        }
+    }
+
+    /// Metric type and labels of the first `MAX_REPORTED_SERIES` failed series,
+    /// e.g. `workload.googleapis.com/requests{code="200"}`
+    pub(crate) fn failed_series(&self, request: &CreateTimeSeriesRequest) -> Vec<String> {
+        self.time_series_indexes
+            .iter()
+            .filter_map(|i| request.time_series.get(*i)?.metric.as_ref())
+            .take(MAX_REPORTED_SERIES)
+            .map(|metric| {
+                let mut labels = metric
+                    .labels
+                    .iter()
+                    .map(|(key, value)| format!("{}={:?}", key, value))
+                    .collect::<Vec<String>>();
+                labels.sort();
+                format!("{}{{{}}}", metric.r#type, labels.join(","))
+            })
+            .collect()
+    }
+}
+
+/// Outcome of a failed CreateTimeSeries call.
+///
+/// Cloud Monitoring writes every valid point of a request and reports the
+/// rejected ones in the `google.rpc.Status` details of the returned error,
+/// so a failed call is not necessarily a lost chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CreateTimeSeriesFailure {
+    /// the status carried a `CreateTimeSeriesSummary`: the points not listed in
+    /// `errors` were written. Without it nothing is assumed written.
+    pub has_summary: bool,
+    pub total_point_count: i32,
+    pub success_point_count: i32,
+    pub errors: Vec<TimeSeriesError>,
+}
+
+impl CreateTimeSeriesFailure {
+    pub(crate) fn from_status(status: &tonic::Status, request: &CreateTimeSeriesRequest) -> Self {
+        let mut failure = Self {
+            has_summary: false,
+            total_point_count: request.time_series.iter().map(|ts| ts.points.len() as i32).sum(),
+            success_point_count: 0,
+            errors: Vec::new(),
+        };
+        let details = rpc::Status::decode(status.details()).unwrap_or_default();
+        for detail in &details.details {
+            if detail.type_url.ends_with(CREATE_TIME_SERIES_SUMMARY_TYPE) {
+                let Ok(summary) = CreateTimeSeriesSummary::decode(detail.value.as_slice()) else {
+                    continue;
+                };
+                failure.has_summary = true;
+                failure.total_point_count = summary.total_point_count;
+                failure.success_point_count = summary.success_point_count;
+                for error in summary.errors {
+                    let status = error.status.unwrap_or_default();
+                    failure.errors.push(TimeSeriesError {
+                        code: tonic::Code::from_i32(status.code),
+                        time_series_indexes: parse_time_series_indexes(&status.message),
+                        message: status.message,
+                        point_count: error.point_count,
+                    });
+                }
+            } else if detail.type_url.ends_with(CREATE_TIME_SERIES_ERROR_TYPE) {
+                let Ok(error) = CreateTimeSeriesError::decode(detail.value.as_slice()) else {
+                    continue;
+                };
+                #[allow(deprecated)]
+                let (time_series, status) = (error.time_series, error.status.unwrap_or_default());
+                let time_series_indexes = time_series
+                    .and_then(|ts| request.time_series.iter().position(|v| *v == ts))
+                    .into_iter()
+                    .collect();
+                failure.errors.push(TimeSeriesError {
+                    code: tonic::Code::from_i32(status.code),
+                    message: status.message,
+                    point_count: 1,
+                    time_series_indexes,
+                });
+            }
+        }
+        if !failure.has_summary && failure.errors.is_empty() {
+            // no details, assume nothing was written
+            failure.errors.push(TimeSeriesError {
+                code: status.code(),
+                message: status.message().to_string(),
+                point_count: failure.total_point_count,
+                time_series_indexes: parse_time_series_indexes(status.message()),
+            });
+        }
+        failure
+    }
+
+    pub(crate) fn failed_point_count(&self) -> i32 {
+        self.total_point_count - self.success_point_count
+    }
+
+    /// Number of series of a request of `series_count` series that were written,
+    /// a series named by several errors being counted once
+    pub(crate) fn sent_series_count(&self, series_count: usize) -> usize {
+        let mut named = BTreeSet::<usize>::new();
+        let mut unnamed = 0;
+        for error in &self.errors {
+            if error.time_series_indexes.iter().any(|i| *i < series_count) {
+                named.extend(error.time_series_indexes.iter().filter(|i| **i < series_count).copied());
+            } else {
+                unnamed += error.failed_series_count(series_count);
+            }
+        }
+        series_count.saturating_sub(named.len() + unnamed)
+    }
+}
+
+/// Extracts `N` from every `timeSeries[N]` in an error message
+fn parse_time_series_indexes(message: &str) -> Vec<usize> {
+    let mut indexes = message
+        .split("timeSeries[")
+        .skip(1)
+        .filter_map(|s| s.split(']').next()?.parse::<usize>().ok())
+        .collect::<Vec<usize>>();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud_sdk::google::{
+        api::Metric,
+        monitoring::v3::{create_time_series_summary, TimeSeries},
+    };
+    use std::collections::HashMap;
+
+    fn request(len: usize) -> CreateTimeSeriesRequest {
+        CreateTimeSeriesRequest {
+            name: "projects/fake_project_id".to_string(),
+            time_series: (0..len)
+                .map(|_| TimeSeries {
+                    points: vec![Default::default()],
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse_time_series_indexes() {
+        assert_eq!(
+            parse_time_series_indexes(
+                "Field timeSeries[3].points[0] had an invalid value; timeSeries[12]: bad label; timeSeries[3]"
+            ),
+            vec![3, 12]
+        );
+        assert_eq!(parse_time_series_indexes("no index"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn test_from_status_with_summary() {
+        let summary = CreateTimeSeriesSummary {
+            total_point_count: 5,
+            success_point_count: 3,
+            errors: vec![create_time_series_summary::Error {
+                status: Some(rpc::Status {
+                    code: tonic::Code::InvalidArgument as i32,
+                    message: "Field timeSeries[1].metric.labels[0] had an invalid value; timeSeries[4]".to_string(),
+                    details: vec![],
+                }),
+                point_count: 2,
+            }],
+        };
+        let details = rpc::Status {
+            code: tonic::Code::InvalidArgument as i32,
+            message: "One or more TimeSeries could not be written".to_string(),
+            details: vec![prost_types::Any {
+                type_url: format!("type.googleapis.com/{}", CREATE_TIME_SERIES_SUMMARY_TYPE),
+                value: summary.encode_to_vec(),
+            }],
+        };
+        let status = tonic::Status::with_details(
+            tonic::Code::InvalidArgument,
+            "One or more TimeSeries could not be written",
+            details.encode_to_vec().into(),
+        );
+        let failure = CreateTimeSeriesFailure::from_status(&status, &request(5));
+        assert_eq!(
+            failure,
+            CreateTimeSeriesFailure {
+                has_summary: true,
+                total_point_count: 5,
+                success_point_count: 3,
+                errors: vec![TimeSeriesError {
+                    code: tonic::Code::InvalidArgument,
+                    message: "Field timeSeries[1].metric.labels[0] had an invalid value; timeSeries[4]".to_string(),
+                    point_count: 2,
+                    time_series_indexes: vec![1, 4],
+                }],
+            }
+        );
+        assert_eq!(failure.failed_point_count(), 2);
+        assert_eq!(failure.errors[0].failed_series_count(5), 2);
+        assert_eq!(failure.sent_series_count(5), 3);
+    }
+
+    #[test]
+    fn test_sent_series_count() {
+        let error = |point_count: i32, time_series_indexes: Vec<usize>| TimeSeriesError {
+            code: tonic::Code::InvalidArgument,
+            message: "".to_string(),
+            point_count,
+            time_series_indexes,
+        };
+        let failure = CreateTimeSeriesFailure {
+            has_summary: true,
+            total_point_count: 10,
+            success_point_count: 4,
+            errors: vec![error(2, vec![1, 3]), error(2, vec![3, 42]), error(2, vec![])],
+        };
+        assert_eq!(failure.errors[1].failed_series_count(10), 1);
+        assert_eq!(failure.errors[2].failed_series_count(10), 2);
+        // series 3 is named twice, the last error names no series
+        assert_eq!(failure.sent_series_count(10), 6);
+    }
+
+    #[test]
+    fn test_failed_series() {
+        let mut request = request(20);
+        for (i, ts) in request.time_series.iter_mut().enumerate() {
+            ts.metric = Some(Metric {
+                r#type: "workload.googleapis.com/m".to_string(),
+                labels: HashMap::from([("id".to_string(), i.to_string()), ("a".to_string(), "b".to_string())]),
+            });
+        }
+        let error = TimeSeriesError {
+            code: tonic::Code::InvalidArgument,
+            message: "".to_string(),
+            point_count: 15,
+            time_series_indexes: (5..20).chain([42]).collect(),
+        };
+        let failed_series = error.failed_series(&request);
+        assert_eq!(failed_series.len(), MAX_REPORTED_SERIES);
+        assert_eq!(failed_series[0], r#"workload.googleapis.com/m{a="b",id="5"}"#);
+    }
+
+    #[test]
+    fn test_from_status_without_details() {
+        let status = tonic::Status::permission_denied("denied");
+        let failure = CreateTimeSeriesFailure::from_status(&status, &request(2));
+        assert!(!failure.has_summary);
+        assert_eq!(failure.total_point_count, 2);
+        assert_eq!(failure.success_point_count, 0);
+        assert_eq!(failure.failed_point_count(), 2);
+        assert_eq!(failure.errors[0].code, tonic::Code::PermissionDenied);
+    }
+}
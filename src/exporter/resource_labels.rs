@@ -0,0 +1,142 @@
+use super::{metric_filter::Pattern, utils::normalize_label_key};
+use crate::gcloud_sdk::google::monitoring::v3::TimeSeries;
+use opentelemetry_sdk::Resource;
+use regex::Regex;
+
+/// Prefix of the resource labels renamed by `ResourceLabelConflict::PrefixResource`
+const RESOURCE_LABEL_PREFIX: &str = "resource_";
+
+/// What to do when a copied resource attribute and a data point attribute have the same label key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ResourceLabelConflict {
+    /// Keep the data point value, the resource value fills the points without it.
+    #[default]
+    PreferDataPoint,
+    /// Keep the resource value.
+    PreferResource,
+    /// Keep both, the resource label is renamed to `resource_<key>`.
+    PrefixResource,
+}
+
+/// Copies the resource attributes matching the configured patterns onto metric labels
+#[derive(Debug, Default)]
+pub(crate) struct ResourceLabels {
+    patterns: Vec<Regex>,
+    conflict: ResourceLabelConflict,
+}
+
+impl ResourceLabels {
+    pub(crate) fn new(patterns: &[Pattern], conflict: ResourceLabelConflict) -> Self {
+        Self {
+            patterns: patterns.iter().filter_map(Pattern::compile).collect(),
+            conflict,
+        }
+    }
+
+    /// Normalized labels of the resource attributes whose key matches a pattern, sorted by key
+    pub(crate) fn select(&self, resource: &Resource) -> Vec<(String, String)> {
+        if self.patterns.is_empty() {
+            return vec![];
+        }
+        let mut labels = resource
+            .iter()
+            .filter(|(key, _)| self.patterns.iter().any(|p| p.is_match(key.as_str())))
+            .map(|(key, value)| (normalize_label_key(key.as_str()), value.to_string()))
+            .collect::<Vec<(String, String)>>();
+        labels.sort();
+        labels
+    }
+
+    /// Resource labels of a metric whose data points have the label keys `point_keys`
+    pub(crate) fn for_metric(&self, labels: &[(String, String)], point_keys: &[String]) -> Vec<(String, String)> {
+        labels
+            .iter()
+            .map(|(key, value)| {
+                if self.conflict == ResourceLabelConflict::PrefixResource && point_keys.contains(key) {
+                    (format!("{}{}", RESOURCE_LABEL_PREFIX, key), value.clone())
+                } else {
+                    (key.clone(), value.clone())
+                }
+            })
+            .collect()
+    }
+
+    /// Adds the resource labels of a metric (see `for_metric`) to its series.
+    /// Returns whether a data point label was overwritten.
+    pub(crate) fn apply(&self, labels: &[(String, String)], series: &mut [TimeSeries]) -> bool {
+        let mut overwritten = false;
+        for m in series.iter_mut().filter_map(|ts| ts.metric.as_mut()) {
+            for (key, value) in labels {
+                if self.conflict == ResourceLabelConflict::PreferDataPoint {
+                    m.labels.entry(key.clone()).or_insert_with(|| value.clone());
+                } else {
+                    overwritten |= m.labels.insert(key.clone(), value.clone()).is_some();
+                }
+            }
+        }
+        overwritten
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gcloud_sdk::google::api::Metric;
+    use opentelemetry::KeyValue;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_resource_labels() {
+        let resource = Resource::builder_empty()
+            .with_attributes([
+                KeyValue::new("service.name", "demo"),
+                KeyValue::new("service.version", "1.0"),
+                KeyValue::new("k8s.deployment.name", "web"),
+            ])
+            .build();
+        let series = |labels: &[(&str, &str)]| TimeSeries {
+            metric: Some(Metric {
+                r#type: "workload.googleapis.com/m".to_string(),
+                labels: labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            }),
+            ..Default::default()
+        };
+        let labels_of = |ts: &TimeSeries| ts.metric.as_ref().unwrap().labels.clone();
+        let patterns = [
+            Pattern::Glob("service.version".to_string()),
+            Pattern::Regex("k8s\\..*".to_string()),
+        ];
+
+        let resource_labels = ResourceLabels::new(&patterns, ResourceLabelConflict::PreferDataPoint);
+        let labels = resource_labels.select(&resource);
+        assert_eq!(
+            labels,
+            vec![
+                ("k8s_deployment_name".to_string(), "web".to_string()),
+                ("service_version".to_string(), "1.0".to_string()),
+            ]
+        );
+        let mut all_series = vec![series(&[("service_version", "2.0")])];
+        resource_labels.apply(&labels, &mut all_series);
+        assert_eq!(
+            labels_of(&all_series[0]),
+            HashMap::from([
+                ("k8s_deployment_name".to_string(), "web".to_string()),
+                ("service_version".to_string(), "2.0".to_string()),
+            ])
+        );
+
+        let resource_labels = ResourceLabels::new(&patterns, ResourceLabelConflict::PrefixResource);
+        let labels = resource_labels.for_metric(&labels, &["service_version".to_string()]);
+        let mut all_series = vec![series(&[("service_version", "2.0")])];
+        resource_labels.apply(&labels, &mut all_series);
+        assert_eq!(
+            labels_of(&all_series[0]),
+            HashMap::from([
+                ("k8s_deployment_name".to_string(), "web".to_string()),
+                ("service_version".to_string(), "2.0".to_string()),
+                ("resource_service_version".to_string(), "1.0".to_string()),
+            ])
+        );
+    }
+}
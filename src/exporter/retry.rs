@@ -0,0 +1,290 @@
+use rand::Rng;
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "tokio")]
+use tokio::time::sleep;
+use tracing::Instrument;
+
+/// Longest sleep of a backoff between two reads of the shutdown deadline
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Retry policy used by every Cloud Monitoring RPC made by the exporter.
+///
+/// The delay before retry `n` is `initial_backoff * (backoff_multiplier_percent / 100)^(n - 1)`,
+/// capped at `max_backoff` and randomized by `jitter_percent`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// max_attempts: maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// initial_backoff: delay before the first retry.
+    pub initial_backoff: Duration,
+    /// max_backoff: upper bound of the delay between two attempts.
+    pub max_backoff: Duration,
+    /// backoff_multiplier_percent: growth factor of the delay after each
+    ///     attempt, in percent (`200` doubles the delay).
+    pub backoff_multiplier_percent: u32,
+    /// jitter_percent: percentage in `[0, 100]` by which every delay is
+    ///     randomly shortened or lengthened.
+    pub jitter_percent: u32,
+    /// deadline: total time budget of one RPC including all retries.
+    ///     No retry is started if it would end after the deadline.
+    pub deadline: Option<Duration>,
+    /// retryable_codes: gRPC codes that are worth retrying.
+    pub retryable_codes: Vec<tonic::Code>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+            backoff_multiplier_percent: 200,
+            jitter_percent: 20,
+            deadline: Some(Duration::from_secs(30)),
+            retryable_codes: vec![
+                tonic::Code::Unavailable,
+                tonic::Code::DataLoss,
+                tonic::Code::DeadlineExceeded,
+                tonic::Code::Aborted,
+                tonic::Code::Internal,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Policy that makes a single attempt
+    pub fn no_retry() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_retryable(&self, code: tonic::Code) -> bool {
+        self.retryable_codes.contains(&code)
+    }
+
+    /// Delay before the attempt following `attempt` (1-based), without jitter
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let backoff = self.initial_backoff.as_secs_f64()
+            * (self.backoff_multiplier_percent.max(100) as f64 / 100.0).powi(exponent);
+        Duration::from_secs_f64(backoff.min(self.max_backoff.as_secs_f64()))
+    }
+
+    fn backoff_with_jitter(&self, attempt: u32) -> Duration {
+        let backoff = self.backoff(attempt);
+        if self.jitter_percent == 0 {
+            return backoff;
+        }
+        let jitter = self.jitter_percent.min(100) as f64 / 100.0;
+        backoff.mul_f64(rand::rng().random_range(1.0 - jitter..=1.0 + jitter))
+    }
+
+    /// Runs `call` until it succeeds, fails with a non retryable code, runs out
+    /// of attempts or the next attempt would end after the deadline.
+    /// `call` receives the attempt number (1-based) and the time left before the deadline.
+    /// `shutdown_deadline` is read again before every attempt and during backoffs,
+    /// so a shutdown starting while the RPC is retried cuts the retries short.
+    pub(crate) async fn run<T, F, Fut>(
+        &self,
+        rpc_name: &str,
+        shutdown_deadline: impl Fn() -> Option<Instant>,
+        mut call: F,
+    ) -> Result<T, tonic::Status>
+    where
+        F: FnMut(u32, Option<Duration>) -> Fut,
+        Fut: Future<Output = Result<T, tonic::Status>>,
+    {
+        let policy_deadline = self.deadline.map(|d| Instant::now() + d);
+        let deadline = || match (policy_deadline, shutdown_deadline()) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let time_left = deadline().map(|d| d.saturating_duration_since(Instant::now()));
+            if time_left == Some(Duration::ZERO) {
+                return Err(tonic::Status::deadline_exceeded(format!(
+                    "GCPMetricsExporter: {} deadline exceeded",
+                    rpc_name
+                )));
+            }
+            let span = tracing::debug_span!("rpc", rpc = rpc_name, attempt);
+            let status = match call(attempt, time_left).instrument(span).await {
+                Ok(resp) => return Ok(resp),
+                Err(status) => status,
+            };
+            if !self.is_retryable(status.code()) || attempt >= self.max_attempts {
+                return Err(status);
+            }
+            let backoff = self.backoff_with_jitter(attempt);
+            let retry_at = Instant::now() + backoff;
+            if deadline().is_some_and(|d| retry_at >= d) {
+                return Err(status);
+            }
+            tracing::warn!(
+                rpc = rpc_name,
+                attempt,
+                code = ?status.code(),
+                message = status.message(),
+                backoff_ms = backoff.as_millis() as u64,
+                "GCPMetricsExporter: Retry Cloud Monitoring call"
+            );
+            loop {
+                let now = Instant::now();
+                if now >= retry_at {
+                    break;
+                }
+                if deadline().is_some_and(|d| retry_at >= d) {
+                    return Err(status);
+                }
+                sleep((retry_at - now).min(SHUTDOWN_POLL_INTERVAL)).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    };
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            backoff_multiplier_percent: 200,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(100), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_backoff_with_jitter() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            jitter_percent: 20,
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let backoff = policy.backoff_with_jitter(1);
+            assert!(backoff >= Duration::from_millis(80) && backoff <= Duration::from_millis(120));
+        }
+        let policy = RetryPolicy {
+            jitter_percent: 0,
+            ..policy
+        };
+        assert_eq!(policy.backoff_with_jitter(2), Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_on_non_retryable_code() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let calls = AtomicU32::new(0);
+        let res: Result<(), _> = policy
+            .run(
+                "test",
+                || None,
+                |_, _| async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err(tonic::Status::invalid_argument("bad"))
+                },
+            )
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::InvalidArgument);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_respects_max_attempts() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let calls = AtomicU32::new(0);
+        let res: Result<(), _> = policy
+            .run(
+                "test",
+                || None,
+                |_, _| async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err(tonic::Status::unavailable("down"))
+                },
+            )
+            .await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::Unavailable);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_run_sees_later_shutdown_deadline() {
+        let policy = RetryPolicy {
+            max_attempts: 100,
+            initial_backoff: Duration::from_secs(1),
+            jitter_percent: 0,
+            ..Default::default()
+        };
+        let shutdown_deadline = Mutex::new(None);
+        let calls = AtomicU32::new(0);
+        let started = Instant::now();
+        let run = policy.run(
+            "test",
+            || *shutdown_deadline.lock().unwrap(),
+            |_, _| async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err::<(), _>(tonic::Status::unavailable("down"))
+            },
+        );
+        // a shutdown starting during the first backoff leaves no time for a retry
+        let shutdown = async {
+            sleep(Duration::from_millis(50)).await;
+            *shutdown_deadline.lock().unwrap() = Some(Instant::now() + Duration::from_millis(50));
+        };
+        let (res, ()) = futures_util::future::join(run, shutdown).await;
+        assert_eq!(res.unwrap_err().code(), tonic::Code::Unavailable);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn test_run_respects_deadline() {
+        let policy = RetryPolicy {
+            max_attempts: 100,
+            initial_backoff: Duration::from_millis(40),
+            jitter_percent: 0,
+            deadline: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let calls = AtomicU32::new(0);
+        let res: Result<(), _> = policy
+            .run(
+                "test",
+                || None,
+                |_, _| async {
+                    calls.fetch_add(1, Ordering::SeqCst);
+                    Err(tonic::Status::unavailable("down"))
+                },
+            )
+            .await;
+        assert!(res.is_err());
+        assert!(calls.load(Ordering::SeqCst) < 4);
+    }
+}
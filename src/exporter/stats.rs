@@ -0,0 +1,149 @@
+use super::DescriptorConflict;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Snapshot of the exporter self-observability counters.
+/// All counters are cumulative since the exporter was created.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExporterStats {
+    /// exports: number of `export` calls.
+    pub exports: u64,
+    /// failed_exports: number of `export` calls that returned an error.
+    pub failed_exports: u64,
+    /// last_export_duration: how long the last `export` call took.
+    pub last_export_duration: Option<Duration>,
+    /// max_export_duration: the longest `export` call so far.
+    pub max_export_duration: Duration,
+    /// series_sent: number of time series written to Cloud Monitoring.
+    pub series_sent: u64,
+    /// series_failed: number of time series that could not be written,
+    ///     keyed by the gRPC code explaining why (e.g. "InvalidArgument").
+    pub series_failed: HashMap<String, u64>,
+    /// rpc_count: number of Cloud Monitoring calls, including retries.
+    pub rpc_count: u64,
+    /// rpc_duration_total: total time spent waiting for Cloud Monitoring calls.
+    pub rpc_duration_total: Duration,
+    /// rpc_duration_max: the slowest Cloud Monitoring call so far.
+    pub rpc_duration_max: Duration,
+    /// retries: number of retried Cloud Monitoring calls.
+    pub retries: u64,
+    /// descriptor_cache_size: number of cached metric descriptors.
+    pub descriptor_cache_size: usize,
+    /// descriptor_conflicts: metrics whose descriptor conflicts with the one
+    ///     stored in Cloud Monitoring, see `DescriptorConflictResolution`.
+    pub descriptor_conflicts: Vec<DescriptorConflict>,
+    /// label_alterations: number of labels altered to fit Cloud Monitoring
+    ///     limits, keyed by alteration (e.g. "LabelValueTruncated"), see `GcmLimits`.
+    pub label_alterations: HashMap<String, u64>,
+}
+
+/// Shared handle to the exporter counters. It stays usable after the exporter
+/// has been moved into a reader, see `GCPMetricsExporter::stats_handle`.
+#[derive(Debug, Clone, Default)]
+pub struct ExporterStatsHandle(Arc<Mutex<ExporterStats>>);
+
+impl ExporterStatsHandle {
+    fn update(&self, f: impl FnOnce(&mut ExporterStats)) {
+        if let Ok(mut stats) = self.0.lock() {
+            f(&mut stats);
+        }
+    }
+
+    pub(crate) fn record_export(&self, duration: Duration, ok: bool) {
+        self.update(|stats| {
+            stats.exports += 1;
+            if !ok {
+                stats.failed_exports += 1;
+            }
+            stats.last_export_duration = Some(duration);
+            stats.max_export_duration = stats.max_export_duration.max(duration);
+        });
+    }
+
+    pub(crate) fn record_rpc(&self, duration: Duration) {
+        self.update(|stats| {
+            stats.rpc_count += 1;
+            stats.rpc_duration_total += duration;
+            stats.rpc_duration_max = stats.rpc_duration_max.max(duration);
+        });
+    }
+
+    pub(crate) fn record_retry(&self) {
+        self.update(|stats| stats.retries += 1);
+    }
+
+    pub(crate) fn record_series_sent(&self, count: u64) {
+        self.update(|stats| stats.series_sent += count);
+    }
+
+    pub(crate) fn record_series_failed(&self, code: tonic::Code, count: u64) {
+        self.update(|stats| *stats.series_failed.entry(format!("{:?}", code)).or_default() += count);
+    }
+
+    pub(crate) fn record_descriptor_cache_size(&self, size: usize) {
+        self.update(|stats| stats.descriptor_cache_size = size);
+    }
+
+    /// Returns false if this conflict was already recorded
+    pub(crate) fn record_descriptor_conflict(&self, conflict: &DescriptorConflict) -> bool {
+        let mut recorded = false;
+        self.update(|stats| {
+            if !stats.descriptor_conflicts.contains(conflict) {
+                stats
+                    .descriptor_conflicts
+                    .retain(|c| c.metric_type != conflict.metric_type);
+                stats.descriptor_conflicts.push(conflict.clone());
+                recorded = true;
+            }
+        });
+        recorded
+    }
+
+    pub(crate) fn record_label_alteration(&self, alteration: &str, count: u64) {
+        self.update(|stats| *stats.label_alterations.entry(alteration.to_string()).or_default() += count);
+    }
+
+    pub fn snapshot(&self) -> ExporterStats {
+        self.0.lock().map(|stats| stats.clone()).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_recorder() {
+        let recorder = ExporterStatsHandle::default();
+        recorder.record_export(Duration::from_millis(30), true);
+        recorder.record_export(Duration::from_millis(10), false);
+        recorder.record_rpc(Duration::from_millis(5));
+        recorder.record_rpc(Duration::from_millis(7));
+        recorder.record_retry();
+        recorder.record_series_sent(10);
+        recorder.record_series_failed(tonic::Code::InvalidArgument, 2);
+        recorder.record_series_failed(tonic::Code::InvalidArgument, 1);
+        recorder.record_descriptor_cache_size(4);
+        assert_eq!(
+            recorder.snapshot(),
+            ExporterStats {
+                exports: 2,
+                failed_exports: 1,
+                last_export_duration: Some(Duration::from_millis(10)),
+                max_export_duration: Duration::from_millis(30),
+                series_sent: 10,
+                series_failed: HashMap::from([("InvalidArgument".to_string(), 3)]),
+                rpc_count: 2,
+                rpc_duration_total: Duration::from_millis(12),
+                rpc_duration_max: Duration::from_millis(7),
+                retries: 1,
+                descriptor_cache_size: 4,
+                descriptor_conflicts: vec![],
+                label_alterations: HashMap::new(),
+            }
+        );
+    }
+}
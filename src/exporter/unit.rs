@@ -0,0 +1,187 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+/// Spellings used by instrumentations that Cloud Monitoring doesn't accept
+const UNIT_TRANSLATIONS: &[(&str, &str)] = &[
+    ("bits", "bit"),
+    ("byte", "By"),
+    ("bytes", "By"),
+    ("kilobytes", "kBy"),
+    ("megabytes", "MBy"),
+    ("gigabytes", "GBy"),
+    ("nanoseconds", "ns"),
+    ("μs", "us"),
+    ("microseconds", "us"),
+    ("milliseconds", "ms"),
+    ("sec", "s"),
+    ("second", "s"),
+    ("seconds", "s"),
+    ("minute", "min"),
+    ("minutes", "min"),
+    ("hour", "h"),
+    ("hours", "h"),
+    ("day", "d"),
+    ("days", "d"),
+    ("Hz", "1/s"),
+    ("percent", "%"),
+];
+
+const UNITS: &[&str] = &["bit", "By", "s", "min", "h", "d"];
+const PREFIXES: &[&str] = &[
+    "k", "M", "G", "T", "P", "E", "Z", "Y", "m", "u", "n", "p", "f", "a", "z", "y", "Ki", "Mi", "Gi", "Ti", "Pi", "Ei",
+    "Zi", "Yi",
+];
+
+/// Translates OpenTelemetry (UCUM) units into units accepted by Cloud Monitoring,
+/// see https://cloud.google.com/monitoring/api/ref_v3/rpc/google.api#metricdescriptor
+#[derive(Debug, Default)]
+pub(crate) struct UnitTranslator {
+    overrides: HashMap<String, String>,
+    reported: Mutex<HashSet<String>>,
+}
+
+impl UnitTranslator {
+    pub(crate) fn new(overrides: HashMap<String, String>) -> Self {
+        Self {
+            overrides,
+            reported: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Returns the Cloud Monitoring unit of `unit`. User overrides win over the
+    /// built-in table, unknown units are kept as an annotation (`{unit}`) and
+    /// reported once.
+    pub(crate) fn translate(&self, unit: &str) -> String {
+        if let Some(gcm_unit) = self.overrides.get(unit) {
+            return gcm_unit.clone();
+        }
+        if let Some((_, gcm_unit)) = UNIT_TRANSLATIONS.iter().find(|(otel_unit, _)| *otel_unit == unit) {
+            return gcm_unit.to_string();
+        }
+        if is_valid_gcm_unit(unit) {
+            return unit.to_string();
+        }
+        let gcm_unit = format!("{{{}}}", unit.replace(['{', '}'], ""));
+        if self.reported.lock().unwrap().insert(unit.to_string()) {
+            tracing::warn!(
+                unit = unit,
+                gcm_unit = %gcm_unit,
+                "GCPMetricsExporter: unit not supported by Cloud Monitoring, exported as an annotation"
+            );
+        }
+        gcm_unit
+    }
+}
+
+/// Checks `unit` against the Cloud Monitoring unit grammar:
+/// `Component { "." Component } { "/" Component }`
+fn is_valid_gcm_unit(unit: &str) -> bool {
+    if unit.is_empty() {
+        return true;
+    }
+    split_outside_annotations(unit, '/').into_iter().all(|expression| {
+        split_outside_annotations(expression, '.')
+            .into_iter()
+            .all(is_valid_component)
+    })
+}
+
+/// `[PREFIX] UNIT [Annotation]`, `Annotation`, `"1"`, `"%"` or `10^N`
+fn is_valid_component(component: &str) -> bool {
+    let (base, annotation) = match component.find('{') {
+        Some(index) => component.split_at(index),
+        None => (component, ""),
+    };
+    if !annotation.is_empty() {
+        let valid = annotation.len() >= 2
+            && annotation.ends_with('}')
+            && !annotation[1..annotation.len() - 1].contains(['{', '}']);
+        if !valid {
+            return false;
+        }
+    }
+    if base.is_empty() {
+        return !annotation.is_empty();
+    }
+    if base == "1" || base == "%" || UNITS.contains(&base) {
+        return true;
+    }
+    if let Some(exponent) = base.strip_prefix("10^") {
+        return exponent.parse::<i32>().is_ok();
+    }
+    PREFIXES
+        .iter()
+        .any(|prefix| base.strip_prefix(prefix).is_some_and(|unit| UNITS.contains(&unit)))
+}
+
+/// Splits on `separator` except inside `{...}` annotations
+fn split_outside_annotations(s: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                parts.push(&s[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_gcm_unit() {
+        for unit in [
+            "",
+            "1",
+            "%",
+            "s",
+            "ms",
+            "us",
+            "min",
+            "By",
+            "KiBy",
+            "GBy",
+            "bit/s",
+            "By/s",
+            "{request}",
+            "{request}/s",
+            "By{transmitted}/s",
+            "10^2.%",
+            "{req.count}",
+            "kBy.s",
+        ] {
+            assert!(is_valid_gcm_unit(unit), "{}", unit);
+        }
+        for unit in [
+            "myunit", "request", "Hz", "bytes", "{request", "s/", "{a{b}}", "10^x", "Cel",
+        ] {
+            assert!(!is_valid_gcm_unit(unit), "{}", unit);
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        let translator = UnitTranslator::new(HashMap::from([("Cel".to_string(), "{degree_celsius}".to_string())]));
+        assert_eq!(translator.translate("ms"), "ms");
+        assert_eq!(translator.translate("{request}"), "{request}");
+        assert_eq!(translator.translate("1"), "1");
+        assert_eq!(translator.translate("bytes"), "By");
+        assert_eq!(translator.translate("Hz"), "1/s");
+        assert_eq!(translator.translate("Cel"), "{degree_celsius}");
+        assert_eq!(translator.translate("myunit"), "{myunit}");
+        assert_eq!(translator.translate("my{unit}"), "{myunit}");
+        assert_eq!(translator.reported.lock().unwrap().len(), 2);
+    }
+}
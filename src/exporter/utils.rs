@@ -1,11 +1,11 @@
+use crate::gcloud_sdk::google::{api::metric_descriptor::MetricKind, monitoring::v3::TimeSeries};
 use opentelemetry::KeyValue;
-use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+use opentelemetry_sdk::metrics::{
+    data::{AggregatedMetrics, MetricData},
+    Temporality,
+};
 
-use std::collections::HashSet;
-
-pub(crate) fn log_warning(err: String) {
-    //tracing::warn!("{}", err);
-}
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 pub(crate) fn get_data_points_attributes_keys(data: &AggregatedMetrics) -> HashSet<String> {
     let attributes_keys: Vec<String> = match data {
@@ -79,6 +79,86 @@ pub(crate) fn get_data_points_attributes_keys(data: &AggregatedMetrics) -> HashS
     HashSet::from_iter(attributes_keys.into_iter())
 }
 
+/// Attributes of the data points of a metric, in the order of its data points
+pub(crate) fn get_data_points_attributes(data: &AggregatedMetrics) -> Vec<Vec<KeyValue>> {
+    match data {
+        AggregatedMetrics::F64(v) => match v {
+            MetricData::Histogram(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+            MetricData::ExponentialHistogram(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+            MetricData::Sum(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+            MetricData::Gauge(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+        },
+        AggregatedMetrics::I64(v) => match v {
+            MetricData::Histogram(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+            MetricData::ExponentialHistogram(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+            MetricData::Sum(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+            MetricData::Gauge(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+        },
+        AggregatedMetrics::U64(v) => match v {
+            MetricData::Histogram(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+            MetricData::ExponentialHistogram(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+            MetricData::Sum(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+            MetricData::Gauge(m) => m.data_points().map(|p| p.attributes().cloned().collect()).collect(),
+        },
+    }
+}
+
+/// Maps the temporality of monotonic sums and histograms to the GCM metric kind
+pub(crate) fn metric_kind_from_temporality(temporality: Temporality) -> MetricKind {
+    match temporality {
+        Temporality::Delta => MetricKind::Delta,
+        _ => MetricKind::Cumulative,
+    }
+}
+
+/// Identity of a time series: metric type, metric labels and monitored resource
+pub(crate) fn time_series_key(ts: &TimeSeries) -> String {
+    let (metric_type, metric_labels) = ts
+        .metric
+        .as_ref()
+        .map(|m| (m.r#type.as_str(), m.labels.iter().collect::<BTreeMap<_, _>>()))
+        .unwrap_or_default();
+    let (resource_type, resource_labels) = ts
+        .resource
+        .as_ref()
+        .map(|r| (r.r#type.as_str(), r.labels.iter().collect::<BTreeMap<_, _>>()))
+        .unwrap_or_default();
+    format!(
+        "{}{:?}{}{:?}",
+        metric_type, metric_labels, resource_type, resource_labels
+    )
+}
+
+/// Splits time series into rounds of requests holding at most `max_per_request` series.
+/// Cloud Monitoring rejects a request containing two points of the same series, so the
+/// n-th occurrence of a series goes to the n-th round. Requests of one round can be sent
+/// concurrently, rounds must be sent in order.
+pub(crate) fn split_time_series(series: Vec<TimeSeries>, max_per_request: usize) -> Vec<Vec<Vec<TimeSeries>>> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    let mut rounds: Vec<Vec<TimeSeries>> = Vec::new();
+    for ts in series {
+        let occurrence = seen.entry(time_series_key(&ts)).or_insert(0);
+        if rounds.len() <= *occurrence {
+            rounds.push(Vec::new());
+        }
+        rounds[*occurrence].push(ts);
+        *occurrence += 1;
+    }
+    rounds
+        .into_iter()
+        .map(|round| {
+            let mut chunks: Vec<Vec<TimeSeries>> = Vec::new();
+            for ts in round {
+                match chunks.last_mut() {
+                    Some(chunk) if chunk.len() < max_per_request => chunk.push(ts),
+                    _ => chunks.push(vec![ts]),
+                }
+            }
+            chunks
+        })
+        .collect()
+}
+
 use unicode_segmentation::UnicodeSegmentation;
 
 ///Makes the key into a valid GCM label key
@@ -132,4 +212,25 @@ mod tests {
         assert_eq!(normalize_label_key("hyphens-dots.slashes/"), "hyphens_dots_slashes_");
         assert_eq!(normalize_label_key("non_letters_:£¢$∞"), "non_letters______");
     }
+
+    #[test]
+    fn test_split_time_series() {
+        let ts = |metric_type: &str, label: &str| TimeSeries {
+            metric: Some(crate::gcloud_sdk::google::api::Metric {
+                r#type: metric_type.to_string(),
+                labels: HashMap::from([("key".to_string(), label.to_string())]),
+            }),
+            ..Default::default()
+        };
+        let series = vec![ts("a", "1"), ts("a", "2"), ts("a", "1"), ts("b", "1"), ts("a", "1")];
+        let rounds = split_time_series(series, 2);
+        assert_eq!(
+            rounds,
+            vec![
+                vec![vec![ts("a", "1"), ts("a", "2")], vec![ts("b", "1")]],
+                vec![vec![ts("a", "1")]],
+                vec![vec![ts("a", "1")]],
+            ]
+        );
+    }
 }
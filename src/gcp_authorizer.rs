@@ -1,4 +1,4 @@
-use crate::gcp_authorizer_error::GcpAuthorizerError;
+use crate::{gcp_authorizer_error::GcpAuthorizerError, EndpointConfig};
 use async_trait::async_trait;
 use hyper::Uri;
 use std::time::Duration;
@@ -9,19 +9,28 @@ impl GoogleEnvironment {
     pub async fn init_google_services_channel<S: AsRef<str>>(
         api_url: S,
     ) -> Result<Channel, crate::error::Error> {
-        let api_url_string = api_url.as_ref().to_string();
-        let uri = Uri::from_maybe_shared(api_url_string)?;
-        if uri.authority().is_none() {
+        Self::init_channel(&EndpointConfig::tls(api_url)).await
+    }
+
+    /// Connects to the given endpoint, with TLS or plaintext as configured.
+    pub async fn init_channel(endpoint: &EndpointConfig) -> Result<Channel, crate::error::Error> {
+        let uri = Uri::from_maybe_shared(endpoint.url.clone())?;
+        let Some(authority) = uri.authority() else {
             return Err(crate::error::ErrorKind::UrlErrorInvalidAuthority(
                 "domain is required".to_string(),
             )
             .into());
-        }
-        let domain_name = uri.authority().unwrap().host().to_string();
-        let tls_config = Self::init_tls_config(domain_name);
+        };
+        let domain_name = endpoint
+            .tls_domain_name
+            .clone()
+            .unwrap_or_else(|| authority.host().to_string());
 
-        Ok(Channel::builder(uri)
-            .tls_config(tls_config)?
+        let mut builder = Channel::builder(uri);
+        if endpoint.tls {
+            builder = builder.tls_config(Self::init_tls_config(domain_name))?;
+        }
+        Ok(builder
             .connect_timeout(Duration::from_secs(30))
             .tcp_keepalive(Some(Duration::from_secs(60)))
             .keep_alive_timeout(Duration::from_secs(60))
@@ -49,6 +58,17 @@ mod tests {
                 .await
                 .unwrap();
     }
+
+    #[tokio::test]
+    async fn test_init_channel_requires_authority() {
+        let err = GoogleEnvironment::init_channel(&EndpointConfig::plaintext("/monitoring"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            crate::error::ErrorKind::UrlErrorInvalidAuthority(_)
+        ));
+    }
 }
 
 pub struct FakeAuthorizer;
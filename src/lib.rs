@@ -7,9 +7,30 @@ mod exporter;
 mod gcp_auth_authorizer;
 pub mod gcp_authorizer;
 pub mod gcp_authorizer_error;
+pub use exporter::AttributeRules;
+pub use exporter::DescriptorConflict;
+pub use exporter::DescriptorConflictResolution;
+pub use exporter::DetectedMonitoredResource;
+pub use exporter::EndpointConfig;
+pub use exporter::ExcessLabelsPolicy;
+pub use exporter::ExporterStats;
+pub use exporter::ExporterStatsHandle;
+pub use exporter::FallbackMonitoredResource;
 pub use exporter::GCPMetricsExporter;
 pub use exporter::GCPMetricsExporterConfig;
+pub use exporter::GcmLimits;
+pub use exporter::LabelDriftPolicy;
+pub use exporter::LongLabelValuePolicy;
+pub use exporter::MetricFilterRule;
+pub use exporter::MetricTypeFn;
 pub use exporter::MonitoredResourceDataConfig;
+pub use exporter::MonitoredResourceMapper;
+pub use exporter::Pattern;
+pub use exporter::PrefixRule;
+pub use exporter::ResourceLabelConflict;
+pub use exporter::RetryPolicy;
+pub use exporter::StaticMonitoredResource;
+pub use exporter::TemplateMonitoredResource;
 mod gcloud_sdk;
 #[cfg(test)]
 mod tests;
@@ -14,7 +14,7 @@ mod tests {
 
     use opentelemetry::metrics::MeterProvider;
     use opentelemetry::KeyValue;
-    use opentelemetry_sdk::metrics::{InstrumentKind, StreamBuilder};
+    use opentelemetry_sdk::metrics::{InstrumentKind, StreamBuilder, Temporality};
     use opentelemetry_sdk::runtime;
     use opentelemetry_sdk::{
         metrics::{periodic_reader_with_async_runtime::PeriodicReader, Aggregation, Instrument, SdkMeterProvider},
@@ -91,7 +91,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Distribution.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "myhistogram".to_string(),
                 metadata: None,
@@ -187,7 +187,10 @@ mod tests {
                                                 count: 10000,
                                                 mean: 4999.5,
                                                 sum_of_squared_deviation: 0.0,
-                                                range: None,
+                                                range: Some(gcloud_sdk::google::api::distribution::Range {
+                                                    min: 0.0,
+                                                    max: 9999.0,
+                                                }),
                                                 bucket_options: Some(
                                                     gcloud_sdk::google::api::distribution::BucketOptions {
                                                         options: Some(
@@ -241,7 +244,7 @@ mod tests {
                             ),
                         },
                     ],
-                    unit: "myunit".to_string(),
+                    unit: "{myunit}".to_string(),
                 },
             ],
         };
@@ -342,7 +345,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Distribution.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "my_single_bucket_histogram".to_string(),
                 metadata: None,
@@ -439,7 +442,10 @@ mod tests {
                                                 count: 10000,
                                                 mean: 4999.5,
                                                 sum_of_squared_deviation: 0.0,
-                                                range: None,
+                                                range: Some(gcloud_sdk::google::api::distribution::Range {
+                                                    min: 0.0,
+                                                    max: 9999.0,
+                                                }),
                                                 bucket_options: Some(
                                                     gcloud_sdk::google::api::distribution::BucketOptions {
                                                         options: Some(
@@ -465,13 +471,157 @@ mod tests {
                             ),
                         },
                     ],
-                    unit: "myunit".to_string(),
+                    unit: "{myunit}".to_string(),
                 },
             ],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_exponential_histogram() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let exporter = crate::GCPMetricsExporter::fake_new();
+        let reader = PeriodicReader::builder(exporter, runtime::Tokio).build();
+        let my_view_change_aggregation = |i: &Instrument| {
+            if i.name() == "my_exponential_histogram" {
+                if let InstrumentKind::Histogram = i.kind() {
+                    let stream = StreamBuilder::default()
+                        .with_name(i.name().to_string())
+                        .with_description("foo".to_string())
+                        .with_unit(i.unit().to_string())
+                        .with_aggregation(Aggregation::Base2ExponentialHistogram {
+                            max_size: 160,
+                            max_scale: 0,
+                            record_min_max: true,
+                        })
+                        .build();
+                    return stream.ok();
+                }
+            }
+            None
+        };
+        let metrics_provider = SdkMeterProvider::builder()
+            .with_resource(
+                Resource::builder_empty()
+                    .with_attributes(vec![KeyValue::new("service.name", "metric-demo")])
+                    .build(),
+            )
+            .with_reader(reader)
+            .with_view(my_view_change_aggregation)
+            .build();
+
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let histogram = meter
+            .f64_histogram("my_exponential_histogram")
+            .with_description("foo")
+            .with_unit(my_unit())
+            .build();
+        for i in 0..=8 {
+            histogram.record(i as f64, &[KeyValue::new("string", "string")]);
+        }
+        metrics_provider.force_flush().unwrap();
+        let res = calls.read().await;
+        let create_metric_descriptor = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| CreateMetricDescriptorRequest::decode(v.message.as_slice()).unwrap())
+            .collect::<Vec<CreateMetricDescriptorRequest>>();
+        let create_metric_descriptor = create_metric_descriptor.get(0).unwrap().clone();
+
+        let expected_create_metric_descriptor = CreateMetricDescriptorRequest {
+            name: "projects/fake_project_id".to_string(),
+            metric_descriptor: Some(MetricDescriptor {
+                name: "".to_string(),
+                r#type: "workload.googleapis.com/my_exponential_histogram".to_string(),
+                labels: vec![gcloud_sdk::google::api::LabelDescriptor {
+                    key: "string".to_string(),
+                    value_type: gcloud_sdk::google::api::label_descriptor::ValueType::String.into(),
+                    description: "".to_string(),
+                }],
+                metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
+                value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Distribution.into(),
+                unit: "{myunit}".to_string(),
+                description: "foo".to_string(),
+                display_name: "my_exponential_histogram".to_string(),
+                metadata: None,
+                launch_stage: gcloud_sdk::google::api::LaunchStage::Unspecified.into(),
+                monitored_resource_types: Vec::new(),
+            }),
+        };
+        assert_eq_all_sorted!(create_metric_descriptor, expected_create_metric_descriptor);
+
+        let create_time_series = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .map(|v| CreateTimeSeriesRequest::decode(v.message.as_slice()).unwrap())
+            .collect::<Vec<CreateTimeSeriesRequest>>();
+        let mut create_time_series = create_time_series.get(0).unwrap().clone();
+        assert_eq!(
+            create_time_series.time_series[0].points[0]
+                .interval
+                .unwrap()
+                .start_time
+                .is_some(),
+            true
+        );
+        create_time_series.time_series[0].points[0].interval = None;
+        let expected_create_time_series = CreateTimeSeriesRequest {
+            name: "projects/fake_project_id".to_string(),
+            time_series: vec![TimeSeries {
+                metric: Some(gcloud_sdk::google::api::Metric {
+                    r#type: "workload.googleapis.com/my_exponential_histogram".to_string(),
+                    labels: HashMap::from([("string".to_string(), "string".to_string())]),
+                }),
+                resource: Some(gcloud_sdk::google::api::MonitoredResource {
+                    r#type: "generic_node".to_string(),
+                    labels: HashMap::from([
+                        ("location".to_string(), "global".to_string()),
+                        ("namespace".to_string(), "".to_string()),
+                        ("node_id".to_string(), "".to_string()),
+                    ]),
+                }),
+                metadata: None,
+                metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
+                value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Distribution.into(),
+                points: vec![Point {
+                    interval: None,
+                    value: Some(TypedValue {
+                        value: Some(
+                            gcloud_sdk::google::monitoring::v3::typed_value::Value::DistributionValue(
+                                gcloud_sdk::google::api::Distribution {
+                                    count: 9,
+                                    mean: 4.0,
+                                    sum_of_squared_deviation: 0.0,
+                                    range: Some(gcloud_sdk::google::api::distribution::Range { min: 0.0, max: 8.0 }),
+                                    bucket_options: Some(gcloud_sdk::google::api::distribution::BucketOptions {
+                                        options: Some(
+                                            gcloud_sdk::google::api::distribution::bucket_options::Options::ExponentialBuckets(
+                                                gcloud_sdk::google::api::distribution::bucket_options::Exponential {
+                                                    num_finite_buckets: 4,
+                                                    growth_factor: 2.0,
+                                                    scale: 0.5,
+                                                },
+                                            ),
+                                        ),
+                                    }),
+                                    // zero bucket goes to underflow, overflow is always empty
+                                    bucket_counts: [1, 1, 1, 2, 4, 0].to_vec(),
+                                    exemplars: [].to_vec(),
+                                },
+                            ),
+                        ),
+                    }),
+                }],
+                unit: "{myunit}".to_string(),
+            }],
+        };
+        assert_eq_sorted!(create_time_series, expected_create_time_series);
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_up_down_counter_float() {
         let _m = THE_RESOURCE.lock().unwrap();
@@ -533,7 +683,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Gauge.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Double.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "myupdowncounter".to_string(),
                 metadata: None,
@@ -618,7 +768,7 @@ mod tests {
                         )),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
@@ -684,7 +834,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Gauge.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Int64.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "myupdowncounter".to_string(),
                 metadata: None,
@@ -767,7 +917,7 @@ mod tests {
                         value: Some(gcloud_sdk::google::monitoring::v3::typed_value::Value::Int64Value(45)),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
@@ -836,7 +986,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Gauge.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Int64.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "myobservablecounter".to_string(),
                 metadata: None,
@@ -919,7 +1069,7 @@ mod tests {
                         value: Some(gcloud_sdk::google::monitoring::v3::typed_value::Value::Int64Value(45)),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
@@ -988,7 +1138,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Gauge.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Double.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "myobservablecounter".to_string(),
                 metadata: None,
@@ -1073,7 +1223,7 @@ mod tests {
                         )),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
@@ -1142,7 +1292,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Int64.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "myobservablecounter".to_string(),
                 metadata: None,
@@ -1225,7 +1375,7 @@ mod tests {
                         value: Some(gcloud_sdk::google::monitoring::v3::typed_value::Value::Int64Value(45)),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
@@ -1294,7 +1444,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Double.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "myobservablecounter".to_string(),
                 metadata: None,
@@ -1379,7 +1529,7 @@ mod tests {
                         )),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
@@ -1448,7 +1598,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Gauge.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Int64.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "myobservablegauge".to_string(),
                 metadata: None,
@@ -1531,7 +1681,7 @@ mod tests {
                         value: Some(gcloud_sdk::google::monitoring::v3::typed_value::Value::Int64Value(45)),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
@@ -1600,7 +1750,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Gauge.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Double.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "myobservablegauge".to_string(),
                 metadata: None,
@@ -1685,7 +1835,7 @@ mod tests {
                         )),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
@@ -1753,7 +1903,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Int64.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "mycounter".to_string(),
                 metadata: None,
@@ -1836,7 +1986,7 @@ mod tests {
                         value: Some(gcloud_sdk::google::monitoring::v3::typed_value::Value::Int64Value(45)),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
@@ -1904,7 +2054,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Double.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "mycounter".to_string(),
                 metadata: None,
@@ -1989,12 +2139,1407 @@ mod tests {
                         )),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_estimate_sum_of_squared_deviation() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            estimate_sum_of_squared_deviation: true,
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let histogram = meter.f64_histogram("myhistogram_ssd").build();
+        for value in [1.0, 3.0, 3.0, 5.0] {
+            histogram.record(value, &[]);
+        }
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let time_series = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .collect::<Vec<TimeSeries>>();
+        let Some(gcloud_sdk::google::monitoring::v3::typed_value::Value::DistributionValue(distribution)) =
+            time_series[0].points[0].value.clone().unwrap().value
+        else {
+            panic!("not a distribution");
+        };
+        assert_eq!(
+            distribution.range,
+            Some(gcloud_sdk::google::api::distribution::Range { min: 1.0, max: 5.0 })
+        );
+        // every value falls in the (0, 5] bucket, its midpoint is 2.5 and the mean 3
+        assert_eq!(distribution.sum_of_squared_deviation, 1.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_gcm_limits() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            limits: crate::GcmLimits {
+                max_labels: 3,
+                max_label_value_length: 8,
+                excess_labels_policy: crate::ExcessLabelsPolicy::Merge,
+                ..Default::default()
+            },
+            ..fake_config()
+        };
+        let exporter = crate::GCPMetricsExporter::new(
+            std::sync::Arc::new(crate::gcp_authorizer::FakeAuthorizer::new()),
+            config,
+        );
+        let stats = exporter.stats_handle();
+        let reader = PeriodicReader::builder(exporter, runtime::Tokio).build();
+        let metrics_provider = SdkMeterProvider::builder().with_reader(reader).build();
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter-limits").build();
+        counter.add(
+            1,
+            &[
+                KeyValue::new("a", "a_very_long_value"),
+                KeyValue::new("b", "1"),
+                KeyValue::new("c", "2"),
+                KeyValue::new("d", "3"),
+            ],
+        );
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let descriptor = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| CreateMetricDescriptorRequest::decode(v.message.as_slice()).unwrap())
+            .find_map(|msg| msg.metric_descriptor)
+            .unwrap();
+        assert_eq!(descriptor.r#type, "workload.googleapis.com/mycounter_limits");
+        assert_eq!(
+            descriptor.labels.iter().map(|l| l.key.as_str()).collect::<Vec<_>>(),
+            vec!["a", "b", "extra_labels"]
+        );
+        let time_series = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .collect::<Vec<TimeSeries>>();
+        assert_eq!(
+            time_series[0].metric.as_ref().unwrap().labels,
+            HashMap::from([
+                ("a".to_string(), "a_very_l".to_string()),
+                ("b".to_string(), "1".to_string()),
+                ("extra_labels".to_string(), "c=2,d=3".to_string()),
+            ])
+        );
+        assert_eq!(
+            stats.snapshot().label_alterations,
+            HashMap::from([("LabelsMerged".to_string(), 2), ("LabelValueTruncated".to_string(), 1),])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_attribute_rules() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            attribute_rules: crate::AttributeRules {
+                deny: vec!["user.id".to_string()],
+                ..Default::default()
+            },
+            metric_attribute_rules: HashMap::from([(
+                "mycounter_attribute_rules".to_string(),
+                crate::AttributeRules {
+                    rename: HashMap::from([("http.route".to_string(), "route".to_string())]),
+                    ..Default::default()
+                },
+            )]),
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter_attribute_rules").build();
+        counter.add(2, &[KeyValue::new("user.id", "1"), KeyValue::new("http.route", "/a")]);
+        counter.add(3, &[KeyValue::new("user.id", "2"), KeyValue::new("http.route", "/a")]);
+        counter.add(1, &[KeyValue::new("user.id", "1"), KeyValue::new("http.route", "/b")]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let descriptor = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| CreateMetricDescriptorRequest::decode(v.message.as_slice()).unwrap())
+            .find_map(|msg| msg.metric_descriptor)
+            .unwrap();
+        assert_eq!(
+            descriptor.labels.iter().map(|l| l.key.as_str()).collect::<Vec<_>>(),
+            vec!["route"]
+        );
+        let values = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .map(|ts| {
+                (
+                    ts.metric.unwrap().labels.get("route").unwrap().clone(),
+                    ts.points[0].value.clone().unwrap().value.unwrap(),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(
+            values,
+            HashMap::from([
+                ("/a".to_string(), typed_value::Value::Int64Value(5)),
+                ("/b".to_string(), typed_value::Value::Int64Value(1)),
+            ])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_metric_filters() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            exclude_metrics: vec![
+                crate::MetricFilterRule {
+                    scope_name: Some(crate::Pattern::Glob("noisy_*".to_string())),
+                    ..Default::default()
+                },
+                crate::MetricFilterRule {
+                    metric: Some(crate::Pattern::Regex(".*_debug".to_string())),
+                    ..Default::default()
+                },
+            ],
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let noisy_meter = metrics_provider.meter("noisy_crate");
+        meter.u64_counter("mycounter_filtered").build().add(1, &[]);
+        meter.u64_counter("mycounter_debug").build().add(1, &[]);
+        noisy_meter.u64_counter("mycounter_noisy").build().add(1, &[]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let descriptor_types = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| {
+                CreateMetricDescriptorRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .metric_descriptor
+                    .unwrap()
+                    .r#type
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(descriptor_types, vec!["workload.googleapis.com/mycounter_filtered"]);
+        let series_types = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .map(|ts| ts.metric.unwrap().r#type)
+            .collect::<Vec<String>>();
+        assert_eq!(series_types, vec!["workload.googleapis.com/mycounter_filtered"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_prefix_rules() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            prefix: "custom.googleapis.com".to_string(),
+            prefix_rules: vec![crate::PrefixRule {
+                rule: crate::MetricFilterRule {
+                    scope_name: Some(crate::Pattern::Glob("lib_*".to_string())),
+                    ..Default::default()
+                },
+                prefix: "workload.googleapis.com/lib".to_string(),
+            }],
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        metrics_provider
+            .meter("test_cloud_monitoring")
+            .u64_counter("mycounter_business")
+            .build()
+            .add(1, &[]);
+        metrics_provider
+            .meter("lib_http")
+            .u64_counter("mycounter_library")
+            .build()
+            .add(1, &[]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let mut descriptor_types = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| {
+                CreateMetricDescriptorRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .metric_descriptor
+                    .unwrap()
+                    .r#type
+            })
+            .collect::<Vec<String>>();
+        descriptor_types.sort();
+        assert_eq!(
+            descriptor_types,
+            vec![
+                "custom.googleapis.com/mycounter_business",
+                "workload.googleapis.com/lib/mycounter_library",
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_metric_type_fn() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            metric_type_fn: Some(crate::MetricTypeFn::new(|scope, metric| {
+                format!("custom.googleapis.com/{}/{}", scope.name(), metric.replace('.', "/"))
+            })),
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        metrics_provider
+            .meter("orders")
+            .u64_counter("created.total")
+            .build()
+            .add(1, &[]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let series_types = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .map(|ts| ts.metric.unwrap().r#type)
+            .collect::<Vec<String>>();
+        assert_eq!(series_types, vec!["custom.googleapis.com/orders/created/total"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_scope_labels() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            add_scope_labels: true,
+            add_scope_attributes: true,
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        let scope = opentelemetry::InstrumentationScope::builder("my_library")
+            .with_version("1.2.3")
+            .with_attributes([KeyValue::new("component", "client")])
+            .build();
+        let meter = metrics_provider.meter_with_scope(scope);
+        meter
+            .u64_counter("mycounter_scope")
+            .build()
+            .add(1, &[KeyValue::new("string", "string")]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let descriptor = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| CreateMetricDescriptorRequest::decode(v.message.as_slice()).unwrap())
+            .find_map(|msg| msg.metric_descriptor)
+            .unwrap();
+        assert_eq!(
+            descriptor.labels.iter().map(|l| l.key.as_str()).collect::<Vec<_>>(),
+            vec![
+                "instrumentation_source",
+                "instrumentation_version",
+                "component",
+                "string"
+            ]
+        );
+        let time_series = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .collect::<Vec<TimeSeries>>();
+        assert_eq!(
+            time_series[0].metric.as_ref().unwrap().labels,
+            HashMap::from([
+                ("instrumentation_source".to_string(), "my_library".to_string()),
+                ("instrumentation_version".to_string(), "1.2.3".to_string()),
+                ("component".to_string(), "client".to_string()),
+                ("string".to_string(), "string".to_string()),
+            ])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_resource_attributes_to_labels() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            resource_attributes_to_labels: vec![
+                crate::Pattern::Glob("service.version".to_string()),
+                crate::Pattern::Regex("deployment\\..*".to_string()),
+            ],
+            resource_label_conflict: crate::ResourceLabelConflict::PrefixResource,
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(
+            vec![
+                KeyValue::new("service.name", "metric-demo"),
+                KeyValue::new("service.version", "1.0"),
+                KeyValue::new("deployment.environment", "prod"),
+            ],
+            config,
+        );
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        meter
+            .u64_counter("mycounter_resource_labels")
+            .build()
+            .add(1, &[KeyValue::new("service.version", "2.0")]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let descriptor = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| CreateMetricDescriptorRequest::decode(v.message.as_slice()).unwrap())
+            .find_map(|msg| msg.metric_descriptor)
+            .unwrap();
+        assert_eq!(
+            descriptor.labels.iter().map(|l| l.key.as_str()).collect::<Vec<_>>(),
+            vec!["deployment_environment", "resource_service_version", "service_version"]
+        );
+        let time_series = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .collect::<Vec<TimeSeries>>();
+        assert_eq!(
+            time_series[0].metric.as_ref().unwrap().labels,
+            HashMap::from([
+                ("deployment_environment".to_string(), "prod".to_string()),
+                ("resource_service_version".to_string(), "1.0".to_string()),
+                ("service_version".to_string(), "2.0".to_string()),
+            ])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_template_monitored_resource() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            monitored_resource_mapper: Some(std::sync::Arc::new(crate::TemplateMonitoredResource {
+                r#type: "generic_task".to_string(),
+                labels: HashMap::from([
+                    ("location".to_string(), "on-prem".to_string()),
+                    ("namespace".to_string(), "${service.namespace:-default}".to_string()),
+                    ("job".to_string(), "${service.name}".to_string()),
+                    ("task_id".to_string(), "${nomad.alloc.id}".to_string()),
+                ]),
+            })),
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(
+            vec![
+                KeyValue::new("service.name", "metric-demo"),
+                KeyValue::new("nomad.alloc.id", "alloc-1"),
+            ],
+            config,
+        );
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        meter.u64_counter("mycounter_template_resource").build().add(1, &[]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let resources = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .map(|ts| ts.resource.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            resources,
+            vec![gcloud_sdk::google::api::MonitoredResource {
+                r#type: "generic_task".to_string(),
+                labels: HashMap::from([
+                    ("location".to_string(), "on-prem".to_string()),
+                    ("namespace".to_string(), "default".to_string()),
+                    ("job".to_string(), "metric-demo".to_string()),
+                    ("task_id".to_string(), "alloc-1".to_string()),
+                ]),
+            }]
+        );
+    }
+
+    #[derive(Debug)]
+    struct UnknownPlatform;
+
+    impl crate::MonitoredResourceMapper for UnknownPlatform {
+        fn map(&self, _resource: &opentelemetry_sdk::Resource) -> Option<crate::MonitoredResourceDataConfig> {
+            None
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_fallback_monitored_resource() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            monitored_resource_mapper: Some(std::sync::Arc::new(UnknownPlatform)),
+            fallback_monitored_resource: Some(crate::FallbackMonitoredResource {
+                location: "on-prem".to_string(),
+            }),
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(
+            vec![
+                KeyValue::new("service.name", "metric-demo"),
+                KeyValue::new("service.instance.id", "metric-demo-1"),
+            ],
+            config,
+        );
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        meter.u64_counter("mycounter_fallback_resource").build().add(1, &[]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let resources = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .map(|ts| ts.resource)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            resources,
+            vec![Some(gcloud_sdk::google::api::MonitoredResource {
+                r#type: "generic_task".to_string(),
+                labels: HashMap::from([
+                    ("location".to_string(), "on-prem".to_string()),
+                    ("namespace".to_string(), "".to_string()),
+                    ("job".to_string(), "metric-demo".to_string()),
+                    ("task_id".to_string(), "metric-demo-1".to_string()),
+                ]),
+            })]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_per_data_point_monitored_resource() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            monitored_resource_mapper: Some(std::sync::Arc::new(crate::TemplateMonitoredResource {
+                r#type: "k8s_pod".to_string(),
+                labels: HashMap::from([
+                    ("location".to_string(), "${cloud.region:-global}".to_string()),
+                    ("cluster_name".to_string(), "${k8s.cluster.name}".to_string()),
+                    ("namespace_name".to_string(), "${k8s.namespace.name}".to_string()),
+                    ("pod_name".to_string(), "${k8s.pod.name}".to_string()),
+                ]),
+            })),
+            per_data_point_monitored_resource: true,
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(
+            vec![
+                KeyValue::new("service.name", "metrics-gateway"),
+                KeyValue::new("k8s.cluster.name", "prod"),
+            ],
+            config,
+        );
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter_per_point_resource").build();
+        for pod in ["pod-a", "pod-b"] {
+            counter.add(
+                1,
+                &[
+                    KeyValue::new("k8s.namespace.name", "shop"),
+                    KeyValue::new("k8s.pod.name", pod),
+                    KeyValue::new("route", "/"),
+                ],
+            );
+        }
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let descriptor = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| CreateMetricDescriptorRequest::decode(v.message.as_slice()).unwrap())
+            .find_map(|msg| msg.metric_descriptor)
+            .unwrap();
+        assert_eq!(
+            descriptor.labels.iter().map(|l| l.key.as_str()).collect::<Vec<_>>(),
+            vec!["route"]
+        );
+        let mut time_series = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .map(|ts| (ts.resource.unwrap(), ts.metric.unwrap().labels))
+            .collect::<Vec<_>>();
+        time_series.sort_by(|a, b| a.0.labels["pod_name"].cmp(&b.0.labels["pod_name"]));
+        let pod = |name: &str| gcloud_sdk::google::api::MonitoredResource {
+            r#type: "k8s_pod".to_string(),
+            labels: HashMap::from([
+                ("location".to_string(), "global".to_string()),
+                ("cluster_name".to_string(), "prod".to_string()),
+                ("namespace_name".to_string(), "shop".to_string()),
+                ("pod_name".to_string(), name.to_string()),
+            ]),
+        };
+        let labels = HashMap::from([("route".to_string(), "/".to_string())]);
+        assert_eq!(
+            time_series,
+            vec![(pod("pod-a"), labels.clone()), (pod("pod-b"), labels)]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_delta_temporality() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            temporality: Temporality::Delta,
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter_delta").with_unit(my_unit()).build();
+        let histogram = meter.f64_histogram("myhistogram_delta").with_unit(my_unit()).build();
+        let up_down_counter = meter.i64_up_down_counter("myupdowncounter_delta").build();
+        counter.add(10, &[KeyValue::new("string", "string")]);
+        histogram.record(1.0, &[KeyValue::new("string", "string")]);
+        up_down_counter.add(-3, &[KeyValue::new("string", "string")]);
+        metrics_provider.force_flush().unwrap();
+        counter.add(5, &[KeyValue::new("string", "string")]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let metric_kinds = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| {
+                let msg = CreateMetricDescriptorRequest::decode(v.message.as_slice()).unwrap();
+                let descriptor = msg.metric_descriptor.unwrap();
+                (descriptor.r#type, descriptor.metric_kind)
+            })
+            .collect::<HashMap<String, i32>>();
+        let expected_metric_kinds = HashMap::from([
+            (
+                "workload.googleapis.com/mycounter_delta".to_string(),
+                gcloud_sdk::google::api::metric_descriptor::MetricKind::Delta.into(),
+            ),
+            (
+                "workload.googleapis.com/myhistogram_delta".to_string(),
+                gcloud_sdk::google::api::metric_descriptor::MetricKind::Delta.into(),
+            ),
+            (
+                "workload.googleapis.com/myupdowncounter_delta".to_string(),
+                gcloud_sdk::google::api::metric_descriptor::MetricKind::Gauge.into(),
+            ),
+        ]);
+        assert_eq!(metric_kinds, expected_metric_kinds);
+
+        let counter_points = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| CreateTimeSeriesRequest::decode(v.message.as_slice()).unwrap().time_series)
+            .filter(|ts| ts.metric.as_ref().unwrap().r#type == "workload.googleapis.com/mycounter_delta")
+            .map(|ts| {
+                assert_eq!(
+                    ts.metric_kind,
+                    gcloud_sdk::google::api::metric_descriptor::MetricKind::Delta as i32
+                );
+                ts.points[0].clone()
+            })
+            .collect::<Vec<Point>>();
+        assert_eq!(counter_points.len(), 2);
+        let values = counter_points
+            .iter()
+            .map(|p| p.value.clone().unwrap().value.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![
+                gcloud_sdk::google::monitoring::v3::typed_value::Value::Int64Value(10),
+                gcloud_sdk::google::monitoring::v3::typed_value::Value::Int64Value(5),
+            ]
+        );
+        // delta intervals of consecutive exports must not overlap
+        let first = counter_points[0].interval.unwrap();
+        let second = counter_points[1].interval.unwrap();
+        assert!(first.start_time.is_some());
+        assert!(second.start_time.unwrap().seconds >= first.end_time.unwrap().seconds);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_plaintext_endpoint() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            endpoint: crate::EndpointConfig::plaintext("http://[::1]:50051"),
+            ..Default::default()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter").build();
+        counter.add(1, &[]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        assert_eq!(res.get("CreateMetricDescriptor").unwrap().len(), 1);
+        assert_eq!(res.get("CreateTimeSeries").unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_concurrent_create_time_series() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            max_concurrent_requests: 4,
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter").build();
+        for i in 0..1000 {
+            counter.add(1, &[KeyValue::new("id", i)]);
+        }
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let create_time_series = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .map(|v| CreateTimeSeriesRequest::decode(v.message.as_slice()).unwrap())
+            .collect::<Vec<CreateTimeSeriesRequest>>();
+        assert_eq!(create_time_series.len(), 5);
+        assert!(create_time_series.iter().all(|req| req.time_series.len() <= 200));
+        let ids = create_time_series
+            .iter()
+            .flat_map(|req| req.time_series.iter())
+            .map(|ts| ts.metric.as_ref().unwrap().labels.get("id").unwrap().clone())
+            .collect::<std::collections::HashSet<String>>();
+        assert_eq!(ids.len(), 1000);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_exporter_stats() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let _calls = get_gcm_calls().await;
+        let exporter = crate::GCPMetricsExporter::fake_new();
+        let stats = exporter.stats_handle();
+        let reader = PeriodicReader::builder(exporter, runtime::Tokio).build();
+        let metrics_provider = SdkMeterProvider::builder().with_reader(reader).build();
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter").build();
+        counter.add(1, &[KeyValue::new("id", 1)]);
+        counter.add(1, &[KeyValue::new("id", 2)]);
+        metrics_provider.force_flush().unwrap();
+
+        let stats = stats.snapshot();
+        assert_eq!(stats.exports, 1);
+        assert_eq!(stats.failed_exports, 0);
+        assert!(stats.last_export_duration.is_some());
+        assert_eq!(stats.series_sent, 2);
+        assert!(stats.series_failed.is_empty());
+        assert_eq!(stats.rpc_count, 2);
+        assert_eq!(stats.retries, 0);
+        assert_eq!(stats.descriptor_cache_size, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_create_time_series_rejected() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls_with_service(MyMetricService {
+            create_time_series_hook: Some(std::sync::Arc::new(|_| {
+                Some(tonic::Status::permission_denied("permission denied"))
+            })),
+            ..Default::default()
+        })
+        .await;
+        let exporter = crate::GCPMetricsExporter::fake_new();
+        let stats = exporter.stats_handle();
+        let reader = PeriodicReader::builder(exporter, runtime::Tokio).build();
+        let metrics_provider = SdkMeterProvider::builder().with_reader(reader).build();
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter").build();
+        for i in 0..300 {
+            counter.add(1, &[KeyValue::new("id", i)]);
+        }
+        assert!(metrics_provider.force_flush().is_err());
+
+        // a rejected chunk is not a partial success, and doesn't stop the other chunks
+        assert_eq!(calls.read().await.get("CreateTimeSeries").unwrap().len(), 2);
+        let stats = stats.snapshot();
+        assert_eq!(stats.failed_exports, 1);
+        assert_eq!(stats.series_sent, 0);
+        assert_eq!(
+            stats.series_failed,
+            HashMap::from([("PermissionDenied".to_string(), 300)])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_create_time_series_partial_failure() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let events = event_collector();
+        // the fake server rejects the point of the series with id "bad"
+        let calls = get_gcm_calls_with_service(MyMetricService {
+            create_time_series_hook: Some(std::sync::Arc::new(|request: &CreateTimeSeriesRequest| {
+                let bad = request
+                    .time_series
+                    .iter()
+                    .position(|ts| ts.metric.as_ref().unwrap().labels.get("id").unwrap() == "bad")?;
+                let summary = CreateTimeSeriesSummary {
+                    total_point_count: request.time_series.len() as i32,
+                    success_point_count: request.time_series.len() as i32 - 1,
+                    errors: vec![create_time_series_summary::Error {
+                        status: Some(gcloud_sdk::google::rpc::Status {
+                            code: tonic::Code::InvalidArgument as i32,
+                            message: format!("Field timeSeries[{}].points[0].value had an invalid value", bad),
+                            details: vec![],
+                        }),
+                        point_count: 1,
+                    }],
+                };
+                let details = gcloud_sdk::google::rpc::Status {
+                    code: tonic::Code::InvalidArgument as i32,
+                    message: "One or more TimeSeries could not be written".to_string(),
+                    details: vec![prost_types::Any {
+                        type_url: "type.googleapis.com/google.monitoring.v3.CreateTimeSeriesSummary".to_string(),
+                        value: summary.encode_to_vec(),
+                    }],
+                };
+                Some(tonic::Status::with_details(
+                    tonic::Code::InvalidArgument,
+                    "One or more TimeSeries could not be written",
+                    details.encode_to_vec().into(),
+                ))
+            })),
+            ..Default::default()
+        })
+        .await;
+        let exporter = crate::GCPMetricsExporter::fake_new();
+        let stats = exporter.stats_handle();
+        let reader = PeriodicReader::builder(exporter, runtime::Tokio).build();
+        let metrics_provider = SdkMeterProvider::builder().with_reader(reader).build();
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter_partial").build();
+        counter.add(1, &[KeyValue::new("id", "good1")]);
+        counter.add(1, &[KeyValue::new("id", "bad")]);
+        counter.add(1, &[KeyValue::new("id", "good2")]);
+        metrics_provider.force_flush().unwrap();
+
+        assert_eq!(calls.read().await.get("CreateTimeSeries").unwrap().len(), 1);
+        let stats = stats.snapshot();
+        assert_eq!(stats.failed_exports, 0);
+        assert_eq!(stats.series_sent, 2);
+        assert_eq!(stats.series_failed, HashMap::from([("InvalidArgument".to_string(), 1)]));
+        let failed_series = events
+            .events()
+            .into_iter()
+            .filter(|event| event.contains("points failed"))
+            .collect::<Vec<String>>();
+        assert_eq!(failed_series.len(), 1);
+        assert!(failed_series[0].contains("failed_series_count=1"));
+        assert!(failed_series[0].contains(r#"mycounter_partial{id=\"bad\"}"#));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_shutdown_rejects_later_exports() {
+        use opentelemetry_sdk::error::OTelSdkError;
+        use opentelemetry_sdk::metrics::data::ResourceMetrics;
+        use opentelemetry_sdk::metrics::exporter::PushMetricExporter;
+
+        let exporter = crate::GCPMetricsExporter::fake_new();
+        assert!(exporter.force_flush().is_ok());
+        assert!(exporter.export(&ResourceMetrics::default()).await.is_ok());
+        assert!(exporter
+            .shutdown_with_timeout(std::time::Duration::from_secs(1))
+            .is_ok());
+        assert!(matches!(
+            exporter.export(&ResourceMetrics::default()).await,
+            Err(OTelSdkError::AlreadyShutdown)
+        ));
+        assert!(matches!(exporter.force_flush(), Err(OTelSdkError::AlreadyShutdown)));
+        assert!(matches!(
+            exporter.shutdown_with_timeout(std::time::Duration::from_secs(1)),
+            Err(OTelSdkError::AlreadyShutdown)
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_shutdown_waits_for_in_flight_export() {
+        use opentelemetry_sdk::metrics::exporter::PushMetricExporter;
+
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls_with_service(MyMetricService {
+            create_time_series_delay: Some(std::time::Duration::from_millis(500)),
+            ..Default::default()
+        })
+        .await;
+        let exporter = std::sync::Arc::new(crate::GCPMetricsExporter::fake_new());
+        let stats = exporter.stats_handle();
+        let reader = PeriodicReader::builder(SharedExporter(exporter.clone()), runtime::Tokio).build();
+        let metrics_provider = SdkMeterProvider::builder().with_reader(reader).build();
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter").build();
+        counter.add(1, &[]);
+        let flush = std::thread::spawn(move || metrics_provider.force_flush());
+        while calls.read().await.get("CreateTimeSeries").is_none() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // shut down from a task of the single worker, which also runs the in-flight export
+        let started = std::time::Instant::now();
+        let shutdown = tokio::spawn(async move { exporter.shutdown_with_timeout(std::time::Duration::from_secs(5)) })
+            .await
+            .unwrap();
+        assert!(shutdown.is_ok());
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+        assert!(flush.join().unwrap().is_ok());
+        let stats = stats.snapshot();
+        assert_eq!(stats.failed_exports, 0);
+        assert_eq!(stats.series_sent, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_label_drift_updates_descriptor() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let metrics_provider = init_metrics(vec![KeyValue::new("service.name", "metric-demo")]);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter_drift").build();
+        counter.add(1, &[KeyValue::new("first", "a")]);
+        metrics_provider.force_flush().unwrap();
+        counter.add(1, &[KeyValue::new("first", "a"), KeyValue::new("second", "b")]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let descriptor_labels = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| {
+                let msg = CreateMetricDescriptorRequest::decode(v.message.as_slice()).unwrap();
+                let mut keys = msg
+                    .metric_descriptor
+                    .unwrap()
+                    .labels
+                    .into_iter()
+                    .map(|l| l.key)
+                    .collect::<Vec<String>>();
+                keys.sort();
+                keys
+            })
+            .collect::<Vec<Vec<String>>>();
+        assert_eq!(
+            descriptor_labels,
+            vec![
+                vec!["first".to_string()],
+                vec!["first".to_string(), "second".to_string()]
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_label_drift_drop_extra_labels() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls().await;
+        let config = crate::GCPMetricsExporterConfig {
+            label_drift_policy: crate::LabelDriftPolicy::DropExtraLabels,
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter_drift").build();
+        counter.add(1, &[KeyValue::new("first", "a")]);
+        metrics_provider.force_flush().unwrap();
+        counter.add(1, &[KeyValue::new("first", "b"), KeyValue::new("second", "b")]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        assert_eq!(res.get("CreateMetricDescriptor").unwrap().len(), 1);
+        let labels = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .map(|ts| ts.metric.unwrap().labels)
+            .collect::<Vec<HashMap<String, String>>>();
+        assert!(labels.iter().all(|l| !l.contains_key("second")));
+        assert!(labels.contains(&HashMap::from([("first".to_string(), "b".to_string())])));
+    }
+
+    fn existing_descriptor(
+        metric_type: &str,
+        value_type: gcloud_sdk::google::api::metric_descriptor::ValueType,
+    ) -> MetricDescriptor {
+        MetricDescriptor {
+            name: format!("projects/fake_project_id/metricDescriptors/{}", metric_type),
+            r#type: metric_type.to_string(),
+            labels: vec![gcloud_sdk::google::api::LabelDescriptor {
+                key: "first".to_string(),
+                ..Default::default()
+            }],
+            metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
+            value_type: value_type.into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_warm_descriptor_cache() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls_with_descriptors(vec![existing_descriptor(
+            "workload.googleapis.com/mycounter_warm",
+            gcloud_sdk::google::api::metric_descriptor::ValueType::Int64,
+        )])
+        .await;
+        let config = crate::GCPMetricsExporterConfig {
+            warm_descriptor_cache: true,
+            ..fake_config()
+        };
+        let exporter = crate::GCPMetricsExporter::new(
+            std::sync::Arc::new(crate::gcp_authorizer::FakeAuthorizer::new()),
+            config,
+        );
+        exporter.init().await;
+        {
+            // warmed up before the first export
+            let res = calls.read().await;
+            assert_eq!(res.get("ListMetricDescriptors").unwrap().len(), 1);
+            assert!(res.get("CreateTimeSeries").is_none());
+        }
+        let metrics_provider = init_metrics_with_exporter(vec![KeyValue::new("service.name", "metric-demo")], exporter);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter_warm").build();
+        counter.add(1, &[KeyValue::new("first", "a")]);
+        metrics_provider.force_flush().unwrap();
+        counter.add(1, &[KeyValue::new("first", "a")]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let list_requests = res
+            .get("ListMetricDescriptors")
+            .unwrap()
+            .iter()
+            .map(|v| ListMetricDescriptorsRequest::decode(v.message.as_slice()).unwrap())
+            .collect::<Vec<ListMetricDescriptorsRequest>>();
+        assert_eq!(list_requests.len(), 1);
+        assert_eq!(list_requests[0].name, "projects/fake_project_id");
+        assert_eq!(
+            list_requests[0].filter,
+            "metric.type = starts_with(\"workload.googleapis.com/\")"
+        );
+        assert!(res.get("CreateMetricDescriptor").is_none());
+        assert_eq!(res.get("CreateTimeSeries").unwrap().len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_warm_descriptor_cache_detects_conflicts() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls_with_descriptors(vec![existing_descriptor(
+            "workload.googleapis.com/mycounter_conflict",
+            gcloud_sdk::google::api::metric_descriptor::ValueType::Double,
+        )])
+        .await;
+        let config = crate::GCPMetricsExporterConfig {
+            warm_descriptor_cache: true,
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![KeyValue::new("service.name", "metric-demo")], config);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let conflicting = meter.u64_counter("mycounter_conflict").build();
+        let other = meter.u64_counter("mycounter_other").build();
+        conflicting.add(1, &[KeyValue::new("first", "a")]);
+        other.add(1, &[]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        assert_eq!(res.get("CreateMetricDescriptor").unwrap().len(), 1);
+        let metric_types = res
+            .get("CreateTimeSeries")
+            .unwrap()
+            .iter()
+            .flat_map(|v| {
+                CreateTimeSeriesRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .time_series
+            })
+            .map(|ts| ts.metric.unwrap().r#type)
+            .collect::<Vec<String>>();
+        assert_eq!(
+            metric_types,
+            vec!["workload.googleapis.com/mycounter_other".to_string()]
+        );
+    }
+
+    /// Exports one u64 counter "mycounter_conflict" while the fake project
+    /// already has it as a DOUBLE metric
+    async fn export_conflicting_counter(
+        resolution: crate::DescriptorConflictResolution,
+    ) -> (GcmCalls, crate::ExporterStats) {
+        export_conflicting_counter_with_service(
+            resolution,
+            MyMetricService {
+                descriptors: descriptors_store(vec![existing_descriptor(
+                    "workload.googleapis.com/mycounter_conflict",
+                    gcloud_sdk::google::api::metric_descriptor::ValueType::Double,
+                )]),
+                ..Default::default()
+            },
+        )
+        .await
+    }
+
+    async fn export_conflicting_counter_with_service(
+        resolution: crate::DescriptorConflictResolution,
+        metric_service: MyMetricService,
+    ) -> (GcmCalls, crate::ExporterStats) {
+        let calls = get_gcm_calls_with_service(metric_service).await;
+        let config = crate::GCPMetricsExporterConfig {
+            descriptor_conflict_resolution: resolution,
+            ..fake_config()
+        };
+        let exporter = crate::GCPMetricsExporter::new(
+            std::sync::Arc::new(crate::gcp_authorizer::FakeAuthorizer::new()),
+            config,
+        );
+        let stats = exporter.stats_handle();
+        let reader = PeriodicReader::builder(exporter, runtime::Tokio).build();
+        let metrics_provider = SdkMeterProvider::builder().with_reader(reader).build();
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter_conflict").build();
+        counter.add(3, &[KeyValue::new("first", "a")]);
+        metrics_provider.force_flush().unwrap();
+        counter.add(1, &[KeyValue::new("first", "a")]);
+        metrics_provider.shutdown().unwrap();
+        (calls, stats.snapshot())
+    }
+
+    fn sent_time_series(calls: &HashMap<String, Vec<GcmCall>>) -> Vec<TimeSeries> {
+        calls
+            .get("CreateTimeSeries")
+            .map(|calls| {
+                calls
+                    .iter()
+                    .flat_map(|v| {
+                        CreateTimeSeriesRequest::decode(v.message.as_slice())
+                            .unwrap()
+                            .time_series
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_descriptor_conflict_skip_metric() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let (calls, stats) = export_conflicting_counter(crate::DescriptorConflictResolution::SkipMetric).await;
+
+        let res = calls.read().await;
+        assert_eq!(res.get("CreateMetricDescriptor").unwrap().len(), 1);
+        let get_requests = res
+            .get("GetMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| GetMetricDescriptorRequest::decode(v.message.as_slice()).unwrap().name)
+            .collect::<Vec<String>>();
+        assert_eq!(
+            get_requests,
+            vec!["projects/fake_project_id/metricDescriptors/workload.googleapis.com/mycounter_conflict".to_string()]
+        );
+        assert!(sent_time_series(&res).is_empty());
+        assert_eq!(
+            stats.descriptor_conflicts,
+            vec![crate::DescriptorConflict {
+                metric_type: "workload.googleapis.com/mycounter_conflict".to_string(),
+                existing_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative,
+                local_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative,
+                existing_value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Double,
+                local_value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Int64,
+                missing_labels: vec![],
+            }]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_descriptor_conflict_coerce_value_type() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let (calls, stats) = export_conflicting_counter(crate::DescriptorConflictResolution::CoerceValueType).await;
+
+        let res = calls.read().await;
+        assert_eq!(res.get("CreateMetricDescriptor").unwrap().len(), 1);
+        let values = sent_time_series(&res)
+            .into_iter()
+            .map(|ts| {
+                assert_eq!(ts.metric.unwrap().r#type, "workload.googleapis.com/mycounter_conflict");
+                assert_eq!(
+                    ts.value_type,
+                    gcloud_sdk::google::api::metric_descriptor::ValueType::Double as i32
+                );
+                ts.points[0].value.clone().unwrap().value.unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            values,
+            vec![
+                gcloud_sdk::google::monitoring::v3::typed_value::Value::DoubleValue(3.0),
+                gcloud_sdk::google::monitoring::v3::typed_value::Value::DoubleValue(4.0),
+            ]
+        );
+        assert_eq!(stats.descriptor_conflicts.len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_descriptor_conflict_coerce_value_type_label_drift() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let calls = get_gcm_calls_with_descriptors(vec![existing_descriptor(
+            "workload.googleapis.com/mycounter_conflict",
+            gcloud_sdk::google::api::metric_descriptor::ValueType::Double,
+        )])
+        .await;
+        let config = crate::GCPMetricsExporterConfig {
+            descriptor_conflict_resolution: crate::DescriptorConflictResolution::CoerceValueType,
+            ..fake_config()
+        };
+        let metrics_provider = init_metrics_with_config(vec![], config);
+        let meter = metrics_provider.meter("test_cloud_monitoring");
+        let counter = meter.u64_counter("mycounter_conflict").build();
+        counter.add(3, &[KeyValue::new("first", "a")]);
+        metrics_provider.force_flush().unwrap();
+        counter.add(1, &[KeyValue::new("first", "a"), KeyValue::new("second", "b")]);
+        metrics_provider.force_flush().unwrap();
+
+        let res = calls.read().await;
+        let descriptors = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| {
+                let descriptor = CreateMetricDescriptorRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .metric_descriptor
+                    .unwrap();
+                let mut keys = descriptor.labels.into_iter().map(|l| l.key).collect::<Vec<String>>();
+                keys.sort();
+                (descriptor.value_type, keys)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            descriptors,
+            vec![
+                (
+                    gcloud_sdk::google::api::metric_descriptor::ValueType::Int64 as i32,
+                    vec!["first".to_string()]
+                ),
+                (
+                    gcloud_sdk::google::api::metric_descriptor::ValueType::Double as i32,
+                    vec!["first".to_string(), "second".to_string()]
+                ),
+            ]
+        );
+        let drifted = sent_time_series(&res)
+            .into_iter()
+            .find(|ts| ts.metric.as_ref().unwrap().labels.contains_key("second"))
+            .unwrap();
+        assert_eq!(
+            drifted.value_type,
+            gcloud_sdk::google::api::metric_descriptor::ValueType::Double as i32
+        );
+        assert_eq!(drifted.metric.unwrap().labels["second"], "b");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_descriptor_conflict_versioned_metric_type() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        let (calls, _stats) =
+            export_conflicting_counter(crate::DescriptorConflictResolution::VersionedMetricType).await;
+
+        let res = calls.read().await;
+        let descriptor_types = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| {
+                CreateMetricDescriptorRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .metric_descriptor
+                    .unwrap()
+                    .r#type
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(
+            descriptor_types,
+            vec![
+                "workload.googleapis.com/mycounter_conflict".to_string(),
+                "workload.googleapis.com/mycounter_conflict_v2".to_string(),
+            ]
+        );
+        let metric_types = sent_time_series(&res)
+            .into_iter()
+            .map(|ts| ts.metric.unwrap().r#type)
+            .collect::<Vec<String>>();
+        assert_eq!(
+            metric_types,
+            vec![
+                "workload.googleapis.com/mycounter_conflict_v2".to_string(),
+                "workload.googleapis.com/mycounter_conflict_v2".to_string(),
+            ]
+        );
+    }
+
+    /// Types of the descriptors created and of the series written by a conflicting counter
+    /// whose `_v2` descriptor already exists, as `v2` describes it
+    async fn export_versioned_counter(v2: Option<MetricDescriptor>) -> (Vec<String>, Vec<String>) {
+        let mut descriptors = vec![existing_descriptor(
+            "workload.googleapis.com/mycounter_conflict",
+            gcloud_sdk::google::api::metric_descriptor::ValueType::Double,
+        )];
+        descriptors.extend(v2);
+        let (calls, _stats) = export_conflicting_counter_with_service(
+            crate::DescriptorConflictResolution::VersionedMetricType,
+            MyMetricService {
+                descriptors: descriptors_store(descriptors),
+                create_metric_descriptor_hook: Some(std::sync::Arc::new(|descriptor: &MetricDescriptor| {
+                    descriptor
+                        .r#type
+                        .ends_with("_v2")
+                        .then(|| tonic::Status::already_exists("metric descriptor already exists"))
+                })),
+                ..Default::default()
+            },
+        )
+        .await;
+        let res = calls.read().await;
+        let descriptor_types = res
+            .get("CreateMetricDescriptor")
+            .unwrap()
+            .iter()
+            .map(|v| {
+                CreateMetricDescriptorRequest::decode(v.message.as_slice())
+                    .unwrap()
+                    .metric_descriptor
+                    .unwrap()
+                    .r#type
+            })
+            .collect::<Vec<String>>();
+        let metric_types = sent_time_series(&res)
+            .into_iter()
+            .map(|ts| ts.metric.unwrap().r#type)
+            .collect::<Vec<String>>();
+        (descriptor_types, metric_types)
+    }
+
+    fn expected_v3_types() -> (Vec<String>, Vec<String>) {
+        (
+            vec![
+                "workload.googleapis.com/mycounter_conflict".to_string(),
+                "workload.googleapis.com/mycounter_conflict_v2".to_string(),
+                "workload.googleapis.com/mycounter_conflict_v3".to_string(),
+            ],
+            vec!["workload.googleapis.com/mycounter_conflict_v3".to_string(); 2],
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_descriptor_conflict_versioned_metric_type_unchecked_version() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        // the existing _v2 descriptor can't be fetched
+        assert_eq!(export_versioned_counter(None).await, expected_v3_types());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_descriptor_conflict_versioned_metric_type_missing_labels() {
+        let _m = THE_RESOURCE.lock().unwrap();
+        // the existing _v2 descriptor has the same kind and value type, but lacks the label
+        let v2 = MetricDescriptor {
+            labels: vec![],
+            ..existing_descriptor(
+                "workload.googleapis.com/mycounter_conflict_v2",
+                gcloud_sdk::google::api::metric_descriptor::ValueType::Int64,
+            )
+        };
+        assert_eq!(export_versioned_counter(Some(v2)).await, expected_v3_types());
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_invalid_label_keys() {
         let _m = THE_RESOURCE.lock().unwrap();
@@ -2038,7 +3583,7 @@ mod tests {
                 }],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Int64.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "mycounter".to_string(),
                 metadata: None,
@@ -2117,7 +3662,7 @@ mod tests {
                         value: Some(gcloud_sdk::google::monitoring::v3::typed_value::Value::Int64Value(12)),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
@@ -2190,7 +3735,7 @@ mod tests {
                 ],
                 metric_kind: gcloud_sdk::google::api::metric_descriptor::MetricKind::Cumulative.into(),
                 value_type: gcloud_sdk::google::api::metric_descriptor::ValueType::Int64.into(),
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
                 description: "foo".to_string(),
                 display_name: "mycounter".to_string(),
                 metadata: None,
@@ -2275,7 +3820,7 @@ mod tests {
                         value: Some(gcloud_sdk::google::monitoring::v3::typed_value::Value::Int64Value(12)),
                     }),
                 }],
-                unit: "myunit".to_string(),
+                unit: "{myunit}".to_string(),
             }],
         };
         assert_eq_sorted!(create_time_series, expected_create_time_series);
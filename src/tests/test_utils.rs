@@ -28,10 +28,27 @@ pub(crate) struct GcmCall {
 #[cfg(test)]
 pub(crate) type GcmCalls = Arc<RwLock<HashMap<String, Vec<GcmCall>>>>;
 
+/// Status failing a CreateTimeSeries call of the fake server, None writes the series
+#[cfg(test)]
+pub(crate) type CreateTimeSeriesHook =
+    Arc<dyn Fn(&CreateTimeSeriesRequest) -> Option<Status> + Send + Sync>;
+
+/// Status failing a CreateMetricDescriptor call of the fake server, None stores the descriptor
+#[cfg(test)]
+pub(crate) type CreateMetricDescriptorHook = Arc<dyn Fn(&MetricDescriptor) -> Option<Status> + Send + Sync>;
+
 #[cfg(test)]
 #[derive(Default)]
 pub(crate) struct MyMetricService {
     pub calls: GcmCalls,
+    /// metric descriptors that already exist in the fake project, keyed by type
+    pub descriptors: Arc<RwLock<HashMap<String, MetricDescriptor>>>,
+    /// answers CreateMetricDescriptor calls before the descriptors are compared
+    pub create_metric_descriptor_hook: Option<CreateMetricDescriptorHook>,
+    /// answers CreateTimeSeries calls, every series is written when unset
+    pub create_time_series_hook: Option<CreateTimeSeriesHook>,
+    /// how long CreateTimeSeries calls take, e.g. to keep an export in flight
+    pub create_time_series_delay: Option<std::time::Duration>,
 }
 
 #[cfg(test)]
@@ -82,9 +99,9 @@ impl MetricService for MyMetricService {
             .get("user-agent")
             .map(|v| v.to_str().unwrap_or("").to_string())
             .unwrap_or_default();
-        let message = request.into_inner().encode_to_vec();
+        let request = request.into_inner();
         let call = GcmCall {
-            message,
+            message: request.encode_to_vec(),
             user_agent,
         };
         self.calls
@@ -93,6 +110,12 @@ impl MetricService for MyMetricService {
             .entry("CreateTimeSeries".to_string())
             .or_default()
             .push(call);
+        if let Some(delay) = self.create_time_series_delay {
+            tokio::time::sleep(delay).await;
+        }
+        if let Some(status) = self.create_time_series_hook.as_ref().and_then(|hook| hook(&request)) {
+            return Err(status);
+        }
         Ok(Response::new(()))
     }
 
@@ -123,7 +146,24 @@ impl MetricService for MyMetricService {
         }
         CreateMetricDescriptorRequest::decode(message.encode_to_vec().as_slice())
             .map_err(|e| Status::invalid_argument(format!("invalid message: {}", e)))?;
-        Ok(Response::new(message.metric_descriptor.unwrap()))
+        let metric_descriptor = message.metric_descriptor.unwrap();
+        if let Some(status) = self
+            .create_metric_descriptor_hook
+            .as_ref()
+            .and_then(|hook| hook(&metric_descriptor))
+        {
+            return Err(status);
+        }
+        let mut descriptors = self.descriptors.write().await;
+        if let Some(existing) = descriptors.get(&metric_descriptor.r#type) {
+            if existing.metric_kind != metric_descriptor.metric_kind
+                || existing.value_type != metric_descriptor.value_type
+            {
+                return Err(Status::already_exists("metric descriptor already exists"));
+            }
+        }
+        descriptors.insert(metric_descriptor.r#type.clone(), metric_descriptor.clone());
+        Ok(Response::new(metric_descriptor))
     }
 
     async fn delete_metric_descriptor(
@@ -138,35 +178,74 @@ impl MetricService for MyMetricService {
 
     async fn get_metric_descriptor(
         &self,
-        _request: tonic::Request<
+        request: tonic::Request<
             crate::gcloud_sdk::google::monitoring::v3::GetMetricDescriptorRequest,
         >,
     ) -> Result<Response<MetricDescriptor>, Status> {
-        // let md = MetricDescriptor {
-        //     name: "projects/".to_string(),
-        //     ..Default::default()
-        // };
-        // Ok(Response::new(md))
-        unimplemented!()
+        let message = request.into_inner();
+        self.calls
+            .write()
+            .await
+            .entry("GetMetricDescriptor".to_string())
+            .or_default()
+            .push(GcmCall {
+                message: message.encode_to_vec(),
+                user_agent: "".to_string(),
+            });
+        let metric_type = message
+            .name
+            .split("/metricDescriptors/")
+            .nth(1)
+            .unwrap_or_default();
+        self.descriptors
+            .read()
+            .await
+            .get(metric_type)
+            .cloned()
+            .map(Response::new)
+            .ok_or_else(|| Status::not_found("metric descriptor not found"))
     }
 
     async fn list_metric_descriptors(
         &self,
-        _request: tonic::Request<
+        request: tonic::Request<
             crate::gcloud_sdk::google::monitoring::v3::ListMetricDescriptorsRequest,
         >,
     ) -> Result<
         Response<crate::gcloud_sdk::google::monitoring::v3::ListMetricDescriptorsResponse>,
         Status,
     > {
-        // let md = MetricDescriptor {
-        //     name: "projects/".to_string(),
-        //     ..Default::default()
-        // };
-        // let mut resp = crate::gcloud_sdk::google::monitoring::v3::ListMetricDescriptorsResponse::default();
-        // resp.metric_descriptors.push(md);
-        // Ok(Response::new(resp))
-        unimplemented!()
+        let message = request.into_inner();
+        self.calls
+            .write()
+            .await
+            .entry("ListMetricDescriptors".to_string())
+            .or_default()
+            .push(GcmCall {
+                message: message.encode_to_vec(),
+                user_agent: "".to_string(),
+            });
+        // only the `metric.type = starts_with("...")` filter is supported
+        let prefix = message
+            .filter
+            .split('"')
+            .nth(1)
+            .unwrap_or_default()
+            .to_string();
+        let metric_descriptors = self
+            .descriptors
+            .read()
+            .await
+            .values()
+            .filter(|md| md.r#type.starts_with(&prefix))
+            .cloned()
+            .collect();
+        Ok(Response::new(
+            crate::gcloud_sdk::google::monitoring::v3::ListMetricDescriptorsResponse {
+                metric_descriptors,
+                next_page_token: "".to_string(),
+            },
+        ))
     }
 
     async fn get_monitored_resource_descriptor(
@@ -184,14 +263,122 @@ impl MetricService for MyMetricService {
     }
 }
 
+/// Exporter shared between a reader and the test, e.g. to shut it down while
+/// the reader exports
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) struct SharedExporter(pub Arc<crate::GCPMetricsExporter>);
+
+#[cfg(test)]
+impl opentelemetry_sdk::metrics::exporter::PushMetricExporter for SharedExporter {
+    fn export(
+        &self,
+        metrics: &opentelemetry_sdk::metrics::data::ResourceMetrics,
+    ) -> impl std::future::Future<Output = opentelemetry_sdk::error::OTelSdkResult> + Send {
+        self.0.export(metrics)
+    }
+
+    fn force_flush(&self) -> opentelemetry_sdk::error::OTelSdkResult {
+        self.0.force_flush()
+    }
+
+    fn shutdown_with_timeout(&self, timeout: std::time::Duration) -> opentelemetry_sdk::error::OTelSdkResult {
+        self.0.shutdown_with_timeout(timeout)
+    }
+
+    fn temporality(&self) -> opentelemetry_sdk::metrics::Temporality {
+        self.0.temporality()
+    }
+}
+
+/// Fields of the events logged by every test, e.g. to check the exporter warnings
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct EventCollector(Arc<std::sync::Mutex<Vec<String>>>);
+
+#[cfg(test)]
+impl EventCollector {
+    pub(crate) fn events(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl tracing::Subscriber for EventCollector {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        tracing::span::Id::from_u64(1)
+    }
+
+    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+    fn event(&self, event: &tracing::Event<'_>) {
+        let mut fields = Vec::new();
+        event.record(
+            &mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                fields.push(format!("{}={:?}", field.name(), value));
+            },
+        );
+        self.0.lock().unwrap().push(fields.join(" "));
+    }
+
+    fn enter(&self, _span: &tracing::span::Id) {}
+
+    fn exit(&self, _span: &tracing::span::Id) {}
+}
+
+/// Installs the global `EventCollector` on first use, exports run on runtime worker threads
+#[cfg(test)]
+pub(crate) fn event_collector() -> EventCollector {
+    static COLLECTOR: once_cell::sync::Lazy<EventCollector> = once_cell::sync::Lazy::new(|| {
+        let collector = EventCollector::default();
+        tracing::subscriber::set_global_default(collector.clone()).unwrap();
+        collector
+    });
+    COLLECTOR.clone()
+}
+
 #[cfg(test)]
 pub(crate) fn init_metrics(res_attributes: Vec<opentelemetry::KeyValue>) -> SdkMeterProvider {
+    init_metrics_with_config(res_attributes, fake_config())
+}
+
+/// Default config pointed at the fake server started by `get_gcm_calls`
+#[cfg(test)]
+pub(crate) fn fake_config() -> crate::GCPMetricsExporterConfig {
+    crate::GCPMetricsExporterConfig {
+        endpoint: crate::EndpointConfig::plaintext("http://localhost:50051"),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn init_metrics_with_config(
+    res_attributes: Vec<opentelemetry::KeyValue>,
+    config: crate::GCPMetricsExporterConfig,
+) -> SdkMeterProvider {
+    let exporter = crate::GCPMetricsExporter::new(
+        Arc::new(crate::gcp_authorizer::FakeAuthorizer::new()),
+        config,
+    );
+    init_metrics_with_exporter(res_attributes, exporter)
+}
+
+#[cfg(test)]
+pub(crate) fn init_metrics_with_exporter(
+    res_attributes: Vec<opentelemetry::KeyValue>,
+    exporter: crate::GCPMetricsExporter,
+) -> SdkMeterProvider {
     use opentelemetry_sdk::runtime;
 
     let res = Resource::builder_empty()
         .with_attributes(res_attributes.clone())
         .build();
-    let exporter = crate::GCPMetricsExporter::fake_new();
     // let reader = PeriodicReader::builder(exporter).build();
     let reader = PeriodicReader::builder(exporter, runtime::Tokio).build();
     SdkMeterProvider::builder()
@@ -202,11 +389,35 @@ pub(crate) fn init_metrics(res_attributes: Vec<opentelemetry::KeyValue>) -> SdkM
 
 #[cfg(test)]
 pub(crate) async fn get_gcm_calls() -> GcmCalls {
+    get_gcm_calls_with_descriptors(vec![]).await
+}
+
+/// Starts the fake server with `descriptors` already existing in the fake project
+#[cfg(test)]
+pub(crate) async fn get_gcm_calls_with_descriptors(descriptors: Vec<MetricDescriptor>) -> GcmCalls {
+    get_gcm_calls_with_service(MyMetricService {
+        descriptors: descriptors_store(descriptors),
+        ..Default::default()
+    })
+    .await
+}
+
+/// Descriptors of the fake project, keyed by type
+#[cfg(test)]
+pub(crate) fn descriptors_store(descriptors: Vec<MetricDescriptor>) -> Arc<RwLock<HashMap<String, MetricDescriptor>>> {
+    Arc::new(RwLock::new(
+        descriptors
+            .into_iter()
+            .map(|md| (md.r#type.clone(), md))
+            .collect(),
+    ))
+}
+
+/// Starts the fake server with a customized service, e.g. one failing CreateTimeSeries calls
+#[cfg(test)]
+pub(crate) async fn get_gcm_calls_with_service(metric_service: MyMetricService) -> GcmCalls {
     let addr = "[::1]:50051".parse().unwrap();
-    let calls: GcmCalls = Arc::new(RwLock::new(HashMap::new()));
-    let metric_service = MyMetricService {
-        calls: calls.clone(),
-    };
+    let calls = metric_service.calls.clone();
     tokio::time::sleep(std::time::Duration::from_millis(100)).await;
     tokio::spawn(async move {
         println!("Server listening on {}", addr);
@@ -239,6 +450,7 @@ mod tests {
         let calls: GcmCalls = Arc::new(RwLock::new(HashMap::new()));
         let metric_service = MyMetricService {
             calls: calls.clone(),
+            ..Default::default()
         };
 
         tokio::spawn(async move {